@@ -1,4 +1,4 @@
-use crate::command::CommandType;
+use filter_rs::{command::CommandType, level::Level};
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Action {
@@ -9,9 +9,13 @@ pub enum Action {
     // Command specific
     ToggleSpaceMenu,
     ClearCommand,
+    CancelCommand,
     Command(CommandType),
     TypeCommand(char),
     DeleteBackCommand,
+    CycleCaseMode,
+    ToggleWholeWord,
+    ToggleLiteral,
     ExecuteCommand,
 
     // Navigation
@@ -27,4 +31,22 @@ pub enum Action {
     // Toggles
     ToggleLineNumbers,
     ToggleAutoscroll,
+    ToggleRetained,
+    ToggleExpandFolds,
+    ToggleCollapseRecords,
+
+    // Highlight rules
+    DropHighlightRule,
+
+    // Only show lines at or above a level, every line for the lowest
+    MinLevel(Level),
+
+    // Filter layers panel
+    ToggleFilterPanel,
+    SelectPrevFilterLayer,
+    SelectNextFilterLayer,
+    ToggleFilterLayer,
+    DeleteFilterLayer,
+    MoveFilterLayerUp,
+    MoveFilterLayerDown,
 }
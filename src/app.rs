@@ -1,28 +1,60 @@
 use crossterm::event::{self, Event, KeyCode, KeyModifiers, MouseEventKind};
 use ratatui::prelude::*;
 use std::{
-    sync::{Arc, RwLock},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{Receiver, RecvTimeoutError, Sender},
+        Arc,
+    },
+    thread::JoinHandle,
     time::{Duration, Instant},
 };
 
-use crate::{
-    action::Action,
-    command::{Command, CommandBuilder, CommandType, Matcher},
+use crate::action::Action;
+use filter_rs::{
+    command::{Command, CommandBuilder, CommandType, JumpTarget, Matcher},
+    filter::{FilterLayer, FilterStack},
+    format::FormatSet,
+    highlight::{HighlightRule, HighlightRules},
+    ingest::{self, IngestEvent},
+    level::{Level, LevelDetector, LevelFilter},
     new_scroll::PageScrollState,
-    pages::Pages,
-    sync_child,
+    pages::{format_millis, now_millis, Pages, PagesWriter, SharedPages},
+    record::RecordStart,
+    session::{Session, SessionState},
+    source::{self, LineSource, SourceEvent, SourceEventKind, SourceHandle},
+    sync_child::{self, IngestStats, OverflowPolicy},
 };
 
+/// Housekeeping interval for timers when nothing else wakes the loop.
+const TICK_RATE: Duration = Duration::from_secs(1);
+
+/// Everything that can wake the main loop.
+#[derive(Debug)]
+pub enum AppEvent {
+    Terminal(Event),
+    Ingest(IngestEvent),
+    /// Lines matched by the preview started as `generation`
+    PreviewCount {
+        generation: u64,
+        count: usize,
+    },
+}
+
 pub struct ErrorTimer {
     pub error: String,
     pub start: Instant,
 }
 
 impl ErrorTimer {
-    pub fn check(&mut self, duration: Duration) {
-        if self.start.elapsed() > duration {
+    /// returns true if the error was cleared
+    pub fn check(&mut self, duration: Duration) -> bool {
+        if !self.error.is_empty() && self.start.elapsed() > duration {
             self.error.clear();
+            return true;
         }
+        false
     }
 
     pub fn new(error: impl Into<String>) -> Self {
@@ -33,8 +65,55 @@ impl ErrorTimer {
     }
 }
 
+/// Live preview of the filter or search being typed.
+pub struct Preview {
+    generation: u64,
+    /// Set when the preview is replaced, to stop its count early
+    cancel: Arc<AtomicBool>,
+    /// Lines matched, `None` while still counting
+    pub count: Option<usize>,
+}
+
+/// Lines appended per second, measured over roughly one second windows.
+pub struct LineRate {
+    window_start: Instant,
+    window_total: usize,
+    per_sec: usize,
+}
+
+impl LineRate {
+    pub fn new() -> Self {
+        Self {
+            window_start: Instant::now(),
+            window_total: 0,
+            per_sec: 0,
+        }
+    }
+
+    /// `total` is the running count of appended lines, returns true if the rate changed
+    pub fn check(&mut self, total: usize) -> bool {
+        let elapsed = self.window_start.elapsed();
+        if elapsed < Duration::from_secs(1) {
+            return false;
+        }
+        let lines = total.saturating_sub(self.window_total);
+        let per_sec = (lines as f64 / elapsed.as_secs_f64()) as usize;
+        self.window_total = total;
+        self.window_start = Instant::now();
+        std::mem::replace(&mut self.per_sec, per_sec) != per_sec
+    }
+
+    pub fn per_sec(&self) -> usize {
+        self.per_sec
+    }
+}
+
 pub struct App {
-    pub pages: Arc<RwLock<Pages>>,
+    pub pages: SharedPages,
+    /// Ingest thread, which returns the writer once every source has closed
+    pub ingest_handle: Option<JoinHandle<PagesWriter>>,
+    /// Lines already checked against the search query
+    pub seen_lines: usize,
 
     pub scroll_state: PageScrollState,
     pub cmd_builder: CommandBuilder,
@@ -42,54 +121,104 @@ pub struct App {
     pub error_timer: ErrorTimer,
     pub current_size: (u16, u16),
     pub should_quit: bool,
-
-    pub child_handle: Option<sync_child::ChildHandle>,
-    pub stdout_rx: std::sync::mpsc::Receiver<String>,
-    pub child_stdin_tx: std::sync::mpsc::Sender<u8>,
-    pub child_spawn_instant: Instant,
-    pub child_exited: bool,
+    /// Set whenever state visible on screen changed since the last draw
+    pub needs_redraw: bool,
+    pub frame_interval: Duration,
+    pub events_rx: Receiver<AppEvent>,
+    pub events_tx: Sender<AppEvent>,
+
+    /// Running sources, stopped when the app is dropped
+    pub sources: Vec<SourceHandle>,
+    pub source_names: Vec<String>,
+    pub ingest_stats: Arc<IngestStats>,
+    pub line_rate: LineRate,
+    /// Where keystrokes go, the first source taking input until it exits
+    pub input_source: Option<usize>,
     pub title: String,
     pub search_query: Option<Command>,
+    pub session_dir: Option<PathBuf>,
+    pub session_state: Option<SessionState>,
+    /// Selected layer while the filter layers panel is open
+    pub filter_panel: Option<usize>,
+    pub preview: Option<Preview>,
+    preview_generation: u64,
+    pub levels: Arc<LevelDetector>,
 }
 
 impl App {
     pub fn new(
-        child_args: Vec<String>,
-        pages_count: usize,
-        page_capacity: usize,
+        sources: Vec<Box<dyn LineSource>>,
+        pages: Pages,
+        session: Option<Session>,
+        records: Option<RecordStart>,
+        levels: Arc<LevelDetector>,
+        overflow: OverflowPolicy,
+        max_fps: u32,
     ) -> anyhow::Result<Self> {
-        let title = child_args.join(" ");
-
-        let (output_tx, output_rx) = std::sync::mpsc::channel();
-
-        let (child_stdin_tx, child_stdin_rx) = std::sync::mpsc::channel();
-
-        let child_handle = sync_child::spawn_child_process(
-            &child_args,
-            Some(output_tx.clone()),
-            Some(output_tx),
-            Some(child_stdin_rx),
-        )?;
-
-        let pages = Arc::new(RwLock::new(Pages::new(page_capacity, pages_count)));
-        let scroll_state = PageScrollState::new(pages.clone());
+        let source_names: Vec<String> = sources.iter().map(|s| s.name()).collect();
+        let session_dir = session.as_ref().map(|s| s.dir().to_path_buf());
+        let title = match &session_dir {
+            Some(dir) if source_names.is_empty() => dir.display().to_string(),
+            _ => source_names.join(", "),
+        };
+
+        let (output_tx, output_rx, ingest_stats) = sync_child::line_channel(overflow);
+        let sources = source::spawn_sources(sources, &output_tx)?;
+        drop(output_tx);
+        let input_source = sources.iter().position(|s| s.input().is_some());
+
+        let (mut writer, pages) = PagesWriter::new(pages);
+        if let Some(session) = session {
+            writer = writer.with_session(session);
+        }
+        let (events_tx, events_rx) = std::sync::mpsc::channel();
+        let ingest_tx = events_tx.clone();
+        let ingest_handle = ingest::spawn_ingest(
+            output_rx,
+            writer,
+            ingest_stats.clone(),
+            source_names.clone(),
+            records,
+            Some(levels.clone()),
+            move |event| {
+                let _ = ingest_tx.send(AppEvent::Ingest(event));
+            },
+        );
+        let mut scroll_state = PageScrollState::new(pages.clone());
+        scroll_state.set_levels(Some(levels.clone()));
+        scroll_state.set_formats(levels.formats().cloned());
 
         Ok(Self {
             pages,
+            ingest_handle: Some(ingest_handle),
+            seen_lines: 0,
             scroll_state,
-            cmd_builder: CommandBuilder::default(),
+            cmd_builder: CommandBuilder {
+                formats: levels.formats().cloned(),
+                ..Default::default()
+            },
             is_space_toggled: false,
             error_timer: ErrorTimer::new(""),
             current_size: (0, 0),
             should_quit: false,
-
-            child_handle: Some(child_handle),
-            stdout_rx: output_rx,
-            child_stdin_tx,
-            child_spawn_instant: Instant::now(),
-            child_exited: false,
+            needs_redraw: true,
+            frame_interval: Duration::from_secs(1) / max_fps.max(1),
+            events_rx,
+            events_tx,
+
+            sources,
+            source_names,
+            ingest_stats,
+            line_rate: LineRate::new(),
+            input_source,
             title,
             search_query: None,
+            session_dir,
+            session_state: None,
+            filter_panel: None,
+            preview: None,
+            preview_generation: 0,
+            levels,
         })
     }
 
@@ -98,42 +227,75 @@ impl App {
         term: &mut ratatui::Terminal<CrosstermBackend<std::io::Stdout>>,
     ) -> anyhow::Result<()> {
         let mut last_tick = Instant::now();
-        let tick_rate = Duration::from_millis(64); // REDRAW_MILLIS_FRAME_TIME
+        let mut last_draw: Option<Instant> = None;
 
         if let Ok(size) = term.size() {
             self.update(Action::Resize(size.width, size.height)).ok();
         }
+        spawn_terminal_events(self.events_tx.clone());
 
         loop {
             if self.should_quit {
                 break;
             }
 
-            self.poll_child();
-            term.draw(|frame| {
-                crate::main_pane::main_pane_with_page_scroll_draw(frame, self);
-                if self.is_space_toggled {
-                    crate::main_pane::draw_space_menu(frame);
-                }
-            })?;
+            let frame_due = last_draw.is_none_or(|t| t.elapsed() >= self.frame_interval);
+            if self.needs_redraw && frame_due {
+                term.draw(|frame| {
+                    crate::main_pane::main_pane_with_page_scroll_draw(frame, self);
+                    if self.is_space_toggled {
+                        crate::main_pane::draw_space_menu(frame);
+                    }
+                    if let Some(selected) = self.filter_panel {
+                        crate::main_pane::draw_filter_panel(frame, self, selected);
+                    }
+                })?;
+                self.needs_redraw = false;
+                last_draw = Some(Instant::now());
+            }
 
-            let timeout = tick_rate
-                .checked_sub(last_tick.elapsed())
-                .unwrap_or_else(|| Duration::from_secs(0));
+            // Sleep until the next event, the next housekeeping tick, or the
+            // next allowed frame if a redraw is pending
+            let mut timeout = TICK_RATE.saturating_sub(last_tick.elapsed());
+            if self.needs_redraw {
+                if let Some(t) = last_draw {
+                    timeout = timeout.min(self.frame_interval.saturating_sub(t.elapsed()));
+                }
+            }
 
-            if crossterm::event::poll(timeout)? {
-                let event = crossterm::event::read()?;
-                log::info!("Event: {:?}", event);
-                let action = self.get_action(event);
+            match self.events_rx.recv_timeout(timeout) {
+                Ok(AppEvent::Terminal(event)) => {
+                    log::info!("Event: {:?}", event);
+                    let action = self.get_action(event);
 
-                log::info!("Action: {:?}", action);
+                    log::info!("Action: {:?}", action);
 
-                if let Some(action) = action {
-                    self.update(action)?;
+                    if let Some(action) = action {
+                        self.update(action)?;
+                    }
+                }
+                Ok(AppEvent::Ingest(IngestEvent::Published)) => {
+                    self.poll_child();
                 }
+                Ok(AppEvent::Ingest(IngestEvent::Source(event))) => {
+                    self.source_changed(event);
+                }
+                Ok(AppEvent::Ingest(IngestEvent::Closed)) => {
+                    self.finish_sources();
+                }
+                Ok(AppEvent::PreviewCount { generation, count }) => {
+                    if let Some(preview) = &mut self.preview {
+                        if preview.generation == generation {
+                            preview.count = Some(count);
+                            self.needs_redraw = true;
+                        }
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
             }
 
-            if last_tick.elapsed() >= tick_rate {
+            if last_tick.elapsed() >= TICK_RATE {
                 self.update(Action::Tick)?;
                 last_tick = Instant::now();
             }
@@ -161,8 +323,17 @@ impl App {
     }
 
     fn handle_key_event(&self, key: event::KeyEvent) -> Option<Action> {
+        if self.filter_panel.is_some() {
+            return self.handle_filter_panel_key(key);
+        }
         match key.code {
-            KeyCode::Esc => Some(Action::ClearCommand),
+            KeyCode::Esc => {
+                if !matches!(self.cmd_builder.cmd_type, CommandType::None) {
+                    Some(Action::CancelCommand)
+                } else {
+                    Some(Action::ClearCommand)
+                }
+            }
             KeyCode::Backspace => {
                 if !matches!(self.cmd_builder.cmd_type, CommandType::None) {
                     Some(Action::DeleteBackCommand)
@@ -173,7 +344,16 @@ impl App {
             KeyCode::Enter => Some(Action::ExecuteCommand),
             KeyCode::Char(c) => {
                 if !matches!(self.cmd_builder.cmd_type, CommandType::None) {
-                    Some(Action::TypeCommand(c))
+                    if key.modifiers.contains(KeyModifiers::ALT) {
+                        match c {
+                            'c' => Some(Action::CycleCaseMode),
+                            'w' => Some(Action::ToggleWholeWord),
+                            'l' => Some(Action::ToggleLiteral),
+                            _ => None,
+                        }
+                    } else {
+                        Some(Action::TypeCommand(c))
+                    }
                 } else {
                     if self.is_space_toggled {
                         match c {
@@ -181,8 +361,21 @@ impl App {
                             'r' => Some(Action::Command(CommandType::Regex)),
                             'i' => Some(Action::Command(CommandType::Ignore)),
                             'f' => Some(Action::Command(CommandType::Filter)),
+                            'h' => Some(Action::Command(CommandType::Highlight)),
+                            'H' => Some(Action::DropHighlightRule),
+                            'C' => Some(Action::Command(CommandType::Context)),
+                            'v' => Some(Action::Command(CommandType::Columns)),
+                            't' => Some(Action::Command(CommandType::TimeRange)),
+                            '1'..='5' => {
+                                let level = Level::ALL[c as usize - '1' as usize];
+                                Some(Action::MinLevel(level))
+                            }
                             'n' => Some(Action::ToggleLineNumbers),
                             'a' => Some(Action::ToggleAutoscroll),
+                            'R' => Some(Action::ToggleRetained),
+                            'e' => Some(Action::ToggleExpandFolds),
+                            'z' => Some(Action::ToggleCollapseRecords),
+                            'l' => Some(Action::ToggleFilterPanel),
                             ':' => Some(Action::Command(CommandType::JumpTo)),
                             'c' => Some(Action::ClearCommand),
                             'q' => Some(Action::Quit),
@@ -240,7 +433,26 @@ impl App {
         }
     }
 
+    fn handle_filter_panel_key(&self, key: event::KeyEvent) -> Option<Action> {
+        match key.code {
+            KeyCode::Esc | KeyCode::Char('l') => Some(Action::ToggleFilterPanel),
+            KeyCode::Up | KeyCode::Char('k') => Some(Action::SelectPrevFilterLayer),
+            KeyCode::Down | KeyCode::Char('j') => Some(Action::SelectNextFilterLayer),
+            KeyCode::Enter | KeyCode::Char(' ') => Some(Action::ToggleFilterLayer),
+            KeyCode::Char('d') | KeyCode::Delete => Some(Action::DeleteFilterLayer),
+            KeyCode::Char('K') => Some(Action::MoveFilterLayerUp),
+            KeyCode::Char('J') => Some(Action::MoveFilterLayerDown),
+            KeyCode::Char('q') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                Some(Action::Quit)
+            }
+            _ => None,
+        }
+    }
+
     pub fn update(&mut self, action: Action) -> anyhow::Result<()> {
+        if !matches!(action, Action::Tick | Action::SendToChild(_)) {
+            self.needs_redraw = true;
+        }
         match action {
             Action::Quit => {
                 self.should_quit = true;
@@ -250,34 +462,60 @@ impl App {
                 self.scroll_state.set_size(w as usize, h as usize);
             }
             Action::Tick => {
-                self.error_timer.check(Duration::from_secs(2));
-                self.poll_child();
+                let error_cleared = self.error_timer.check(Duration::from_secs(2));
+                let rate_changed = self
+                    .line_rate
+                    .check(self.ingest_stats.appended.load(Ordering::Relaxed));
+                if error_cleared || rate_changed {
+                    self.needs_redraw = true;
+                }
             }
             Action::ToggleSpaceMenu => {
                 self.is_space_toggled = !self.is_space_toggled;
             }
+            Action::CancelCommand => {
+                self.end_preview();
+                self.cmd_builder.clear();
+            }
             Action::ClearCommand => {
                 self.cmd_builder.clear();
-                self.scroll_state.set_filter(None);
+                self.scroll_state.filters_mut().clear();
                 self.scroll_state.set_search_query(None);
                 self.scroll_state.set_cursor(None);
                 self.search_query = None;
                 self.is_space_toggled = false;
+                self.save_session_state();
             }
             Action::Command(cmd_type) => {
                 self.is_space_toggled = false;
                 self.cmd_builder.cmd_type = cmd_type;
                 self.cmd_builder.cmd.clear();
                 self.scroll_state.set_cursor(None);
+                self.end_preview();
             }
             Action::TypeCommand(c) => {
                 self.cmd_builder.cmd.push(c);
+                self.update_preview();
             }
             Action::DeleteBackCommand => {
                 self.cmd_builder.cmd.pop();
+                self.update_preview();
+            }
+            Action::CycleCaseMode => {
+                self.cmd_builder.options.cycle_case();
+                self.update_preview();
+            }
+            Action::ToggleWholeWord => {
+                self.cmd_builder.options.whole_word = !self.cmd_builder.options.whole_word;
+                self.update_preview();
+            }
+            Action::ToggleLiteral => {
+                self.cmd_builder.options.literal = !self.cmd_builder.options.literal;
+                self.update_preview();
             }
             Action::ExecuteCommand => {
                 self.execute_command();
+                self.save_session_state();
             }
             Action::ScrollUp => {
                 self.scroll_state.scroll_up();
@@ -293,17 +531,93 @@ impl App {
                 self.scroll_state.toggle_autoscroll();
                 self.is_space_toggled = false;
             }
+            Action::ToggleExpandFolds => {
+                self.scroll_state.toggle_expand_folds();
+                self.is_space_toggled = false;
+            }
+            Action::ToggleCollapseRecords => {
+                self.scroll_state.toggle_collapse_records();
+                self.is_space_toggled = false;
+            }
+            Action::ToggleRetained => {
+                self.is_space_toggled = false;
+                if self.pages.snapshot().retained().is_none() {
+                    self.error_timer = ErrorTimer::new("No --retain pattern was given");
+                } else {
+                    self.scroll_state.toggle_retained();
+                    self.refresh_matches();
+                }
+            }
+
+            Action::DropHighlightRule => {
+                self.is_space_toggled = false;
+                self.scroll_state.highlights_mut().pop();
+                self.save_session_state();
+            }
+
+            Action::MinLevel(level) => {
+                self.is_space_toggled = false;
+                let filter =
+                    (level > Level::Trace).then(|| LevelFilter::new(level, self.levels.clone()));
+                self.scroll_state.filters_mut().set_level(filter);
+                self.scroll_state.normalize_scroll();
+            }
+
+            Action::ToggleFilterPanel => {
+                self.is_space_toggled = false;
+                self.filter_panel = match self.filter_panel {
+                    Some(_) => None,
+                    None => Some(0),
+                };
+            }
+            Action::SelectPrevFilterLayer => {
+                if let Some(selected) = &mut self.filter_panel {
+                    *selected = selected.saturating_sub(1);
+                }
+            }
+            Action::SelectNextFilterLayer => {
+                let len = self.scroll_state.filters().len();
+                if let Some(selected) = &mut self.filter_panel {
+                    *selected = (*selected + 1).min(len.saturating_sub(1));
+                }
+            }
+            Action::ToggleFilterLayer
+            | Action::DeleteFilterLayer
+            | Action::MoveFilterLayerUp
+            | Action::MoveFilterLayerDown => {
+                if let Some(selected) = self.filter_panel {
+                    let filters = self.scroll_state.filters_mut();
+                    let selected = match action {
+                        Action::ToggleFilterLayer => {
+                            filters.toggle(selected);
+                            selected
+                        }
+                        Action::DeleteFilterLayer => {
+                            filters.remove(selected);
+                            selected.min(filters.len().saturating_sub(1))
+                        }
+                        Action::MoveFilterLayerUp => filters.move_up(selected),
+                        _ => filters.move_down(selected),
+                    };
+                    self.filter_panel = Some(selected);
+                    self.scroll_state.normalize_scroll();
+                    self.save_session_state();
+                }
+            }
 
             Action::SearchNext => {
                 if let Some(query) = &self.search_query {
-                    let pages = self.pages.read().unwrap();
+                    let pages = self.scroll_state.snapshot();
                     // Use cursor_idx as the reference point if available, otherwise bottom_line_idx
                     let current_idx = self
                         .scroll_state
                         .cursor_idx()
                         .unwrap_or(self.scroll_state.bottom_line_idx());
 
-                    if let Some((next_idx, range)) = pages.find_next(query, current_idx) {
+                    let range = self.scroll_state.cursor_range();
+                    if let Some((next_idx, range)) =
+                        pages.find_next_occurrence(query, current_idx, range)
+                    {
                         // jump_to_with_range automatically disables autoscroll
                         self.scroll_state.jump_to_with_range(next_idx, range);
                     }
@@ -311,23 +625,28 @@ impl App {
             }
             Action::SearchPrev => {
                 if let Some(query) = &self.search_query {
-                    let pages = self.pages.read().unwrap();
+                    let pages = self.scroll_state.snapshot();
                     // Use cursor_idx as the reference point if available, otherwise bottom_line_idx
                     let current_idx = self
                         .scroll_state
                         .cursor_idx()
                         .unwrap_or(self.scroll_state.bottom_line_idx());
 
-                    if let Some((prev_idx, range)) = pages.find_prev(query, current_idx) {
+                    let range = self.scroll_state.cursor_range();
+                    if let Some((prev_idx, range)) =
+                        pages.find_prev_occurrence(query, current_idx, range)
+                    {
                         // jump_to_with_range automatically disables autoscroll
                         self.scroll_state.jump_to_with_range(prev_idx, range);
                     }
                 }
             }
             Action::SendToChild(c) => {
-                if !self.child_exited {
+                if let Some(input) = self.input_source.and_then(|i| self.sources[i].input()) {
                     // log::info!("Sending {c} to child process");
-                    self.child_stdin_tx.send(c as u8)?;
+                    if input.send(c as u8).is_err() {
+                        self.input_source = None;
+                    }
                 }
             }
         }
@@ -338,98 +657,343 @@ impl App {
         log::info!("Applying command {:?}", self.cmd_builder);
         match self.cmd_builder.cmd_type {
             CommandType::JumpTo => {
-                if let Ok(line_number) = self.cmd_builder.cmd.parse::<usize>() {
-                    self.scroll_state.jump_to(line_number);
-                } else {
-                    self.error_timer = ErrorTimer::new(format!(
-                        "Unable to parse line number {}",
-                        self.cmd_builder.cmd
-                    ));
+                match JumpTarget::parse(&self.cmd_builder.cmd, now_millis()) {
+                    Ok(target) => self.jump_to_target(target),
+                    Err(err) => {
+                        self.error_timer = ErrorTimer::new(format!("Invalid jump: {}", err))
+                    }
                 }
                 self.cmd_builder.clear();
             }
             CommandType::Search | CommandType::Regex => {
-                if let Some(cmd) = self.cmd_builder.build() {
-                    let pages = self.pages.read().unwrap();
-                    let matches = pages.find_all_matches(&cmd);
-
-                    if let Some((last_match, range)) = pages.find_prev(&cmd, pages.lines_count()) {
-                        if !self.scroll_state.auto_scroll() {
-                            self.scroll_state.jump_to_with_range(last_match, range);
-                        } else {
-                            // If autoscrolling, we just update the cursor position for highlights
-                            // without disabling autoscroll.
-                            self.scroll_state.set_cursor(Some(last_match));
-                        }
+                match self.cmd_builder.try_build() {
+                    Ok(Some(cmd)) => {
+                        self.end_preview();
+                        self.apply_search(cmd);
+                    }
+                    Ok(None) => self.end_preview(),
+                    Err(err) => {
+                        // Keep the prompt open so the pattern can be fixed
+                        self.error_timer = ErrorTimer::new(format!("Invalid regex: {}", err));
+                        return;
                     }
-                    self.scroll_state.set_search_query(Some(cmd.clone()));
-                    self.scroll_state.set_matches(matches);
-                    self.search_query = Some(cmd);
                 }
                 self.cmd_builder.clear();
             }
-            CommandType::Filter => {
-                if let Some(cmd) = self.cmd_builder.build() {
-                    self.scroll_state.set_filter(Some(cmd));
+            CommandType::Filter | CommandType::Ignore | CommandType::TimeRange => {
+                match self.cmd_builder.try_build() {
+                    Ok(Some(cmd)) => {
+                        self.end_preview();
+                        self.scroll_state.filters_mut().push(FilterLayer::new(cmd));
+                    }
+                    Ok(None) => self.end_preview(),
+                    Err(err) => {
+                        self.error_timer = ErrorTimer::new(format!("Invalid filter: {}", err));
+                        return;
+                    }
                 }
                 // Clear cursor highlighting when switching to filter mode
                 self.scroll_state.set_cursor(None);
                 self.cmd_builder.clear();
             }
+            CommandType::Context => {
+                match parse_context(&self.cmd_builder.cmd) {
+                    Some((before, after)) => self.scroll_state.set_context(before, after),
+                    None => {
+                        self.error_timer = ErrorTimer::new(format!(
+                            "Unable to parse context {}, expected N or BEFORE,AFTER",
+                            self.cmd_builder.cmd
+                        ));
+                    }
+                }
+                self.cmd_builder.clear();
+            }
+            CommandType::Columns => {
+                if self.cmd_builder.cmd.trim().is_empty() {
+                    self.scroll_state.set_columns(None);
+                } else {
+                    match self.cmd_builder.cmd.parse() {
+                        Ok(columns) => self.scroll_state.set_columns(Some(columns)),
+                        Err(err) => {
+                            self.error_timer = ErrorTimer::new(format!("Invalid columns: {}", err));
+                            return;
+                        }
+                    }
+                }
+                self.cmd_builder.clear();
+            }
+            CommandType::Highlight => {
+                match HighlightRule::parse_with(&self.cmd_builder.cmd, self.cmd_builder.options) {
+                    Ok(rule) => self.scroll_state.highlights_mut().push(rule),
+                    Err(err) => {
+                        self.error_timer = ErrorTimer::new(format!("Invalid highlight: {}", err));
+                        return;
+                    }
+                }
+                self.cmd_builder.clear();
+            }
             _ => {
                 log::warn!("unimplemented command type");
             }
         }
     }
 
+    fn apply_search(&mut self, cmd: Command) {
+        let pages = self.scroll_state.snapshot();
+        let matches = pages.find_all_matches(&cmd);
+
+        if let Some((last_match, range)) =
+            pages.find_prev_occurrence(&cmd, pages.lines_count(), None)
+        {
+            if !self.scroll_state.auto_scroll() {
+                self.scroll_state.jump_to_with_range(last_match, range);
+            } else {
+                // If autoscrolling, we just update the cursor position for highlights
+                // without disabling autoscroll.
+                self.scroll_state.set_cursor(Some(last_match));
+            }
+        }
+        self.scroll_state.set_search_query(Some(cmd.clone()));
+        self.scroll_state.set_matches(matches);
+        self.seen_lines = pages.lines_count();
+        self.search_query = Some(cmd);
+    }
+
+    /// Formats lines are parsed with, see [`filter_rs::format`].
+    fn formats(&self) -> Option<&FormatSet> {
+        self.levels.formats().map(|formats| formats.as_ref())
+    }
+
+    /// Moves the cursor to `target`, counting relative jumps from the cursor or
+    /// the bottom line.
+    fn jump_to_target(&mut self, target: JumpTarget) {
+        let pages = self.scroll_state.snapshot();
+        let first = pages.first_index();
+        let last = pages.lines_count().saturating_sub(1).max(first);
+        let count = (last - first) as f64;
+        let current = self
+            .scroll_state
+            .cursor_idx()
+            .unwrap_or(self.scroll_state.bottom_line_idx());
+        let offset = |delta: f64| (current as f64 + delta).round().max(0.0) as usize;
+        let idx = match target {
+            JumpTarget::Line(number) => match pages.index_of_number(number) {
+                Some(idx) => idx,
+                None => {
+                    self.error_timer = ErrorTimer::new(format!("No line {}", number));
+                    return;
+                }
+            },
+            JumpTarget::Relative(delta) => offset(delta as f64),
+            JumpTarget::Percent(percent) => first + (count * percent / 100.0).round() as usize,
+            JumpTarget::RelativePercent(percent) => offset(count * percent / 100.0),
+            JumpTarget::Time(time) => match pages.find_time(time, self.formats()) {
+                Some(idx) => idx,
+                None => {
+                    self.error_timer =
+                        ErrorTimer::new(format!("No line at or after {}", format_millis(time)));
+                    return;
+                }
+            },
+        };
+        drop(pages);
+        self.scroll_state.jump_to(idx.clamp(first, last));
+    }
+
+    /// Shows what the command being typed would match, replacing the previous
+    /// preview. The view changes right away, the match count follows from a
+    /// background thread.
+    fn update_preview(&mut self) {
+        // A pattern that doesn't build yet shows what was there before
+        let cmd = self.cmd_builder.try_build().ok().flatten();
+        match self.cmd_builder.cmd_type {
+            CommandType::Search | CommandType::Regex => {
+                let search = cmd.clone().or_else(|| self.search_query.clone());
+                self.scroll_state.set_search_query(search);
+                self.start_preview_count(cmd);
+            }
+            CommandType::Filter | CommandType::Ignore | CommandType::TimeRange => {
+                let filters = self.scroll_state.filters_mut();
+                filters.set_preview(cmd.map(FilterLayer::new));
+                let filters = filters.preview().map(|_| filters.clone());
+                self.scroll_state.normalize_scroll();
+                self.start_preview_count(filters);
+            }
+            _ => {}
+        }
+    }
+
+    /// Counts the lines `matcher` matches on a thread, cancelling any count
+    /// still running.
+    fn start_preview_count<M: Matcher + Send + 'static>(&mut self, matcher: Option<M>) {
+        if let Some(preview) = self.preview.take() {
+            preview.cancel.store(true, Ordering::Relaxed);
+        }
+        let Some(matcher) = matcher else {
+            return;
+        };
+
+        self.preview_generation += 1;
+        let generation = self.preview_generation;
+        let cancel = Arc::new(AtomicBool::new(false));
+        self.preview = Some(Preview {
+            generation,
+            cancel: cancel.clone(),
+            count: None,
+        });
+
+        let pages = self.scroll_state.snapshot();
+        let tx = self.events_tx.clone();
+        std::thread::spawn(move || {
+            if let Some(matches) = pages.find_all_matches_until(&matcher, &cancel) {
+                let _ = tx.send(AppEvent::PreviewCount {
+                    generation,
+                    count: matches.len(),
+                });
+            }
+        });
+    }
+
+    /// Drops the preview, going back to the applied filter and search.
+    fn end_preview(&mut self) {
+        self.start_preview_count(None::<Command>);
+        self.scroll_state.filters_mut().set_preview(None);
+        self.scroll_state
+            .set_search_query(self.search_query.clone());
+        self.scroll_state.normalize_scroll();
+    }
+
+    /// Applies the filter and search of `state`, which is kept up to date in
+    /// the session directory from now on.
+    pub fn set_session_state(&mut self, state: SessionState) {
+        let formats = self.levels.formats().cloned();
+        let filters = state.filters.iter().cloned().map(|mut layer| {
+            layer.command = layer.command.with_formats(formats.clone());
+            layer
+        });
+        *self.scroll_state.filters_mut() = FilterStack::from(filters.collect::<Vec<_>>());
+        *self.scroll_state.highlights_mut() = HighlightRules::from(state.highlights.clone());
+        if let Some(search) = state.search.clone() {
+            self.apply_search(search);
+        }
+        self.session_state = Some(state);
+        self.save_session_state();
+    }
+
+    /// Adds highlight rules given on the command line, on top of any restored
+    /// from the session.
+    pub fn add_highlight_rules(&mut self, rules: Vec<HighlightRule>) {
+        for rule in rules {
+            self.scroll_state.highlights_mut().push(rule);
+        }
+        self.save_session_state();
+    }
+
+    fn save_session_state(&mut self) {
+        let (Some(dir), Some(state)) = (&self.session_dir, &mut self.session_state) else {
+            return;
+        };
+        state.filters = self.scroll_state.filters().layers().to_vec();
+        state.search = self.search_query.clone();
+        state.highlights = self.scroll_state.highlights().rules().to_vec();
+        if let Err(err) = state.save(dir) {
+            log::error!("failed to save session state {}", err);
+        }
+    }
+
     fn poll_child(&mut self) {
-        if !self.child_exited {
-            loop {
-                match self.stdout_rx.try_recv() {
-                    Ok(s) => {
-                        let mut pages = self.pages.write().unwrap();
-                        let old_first_index = pages.first_index();
-                        pages.add_line(&s);
-                        let new_first_index = pages.first_index();
-
-                        if new_first_index > old_first_index {
-                            self.scroll_state.remove_matches_before(new_first_index);
-                        }
+        self.track_new_lines();
+        self.needs_redraw = true;
+    }
 
-                        let pages_len = pages.lines_count();
-                        if let Some(query) = &self.search_query {
-                            let new_line_idx = pages_len.saturating_sub(1);
-                            if let Some(line) = pages.get_line(new_line_idx) {
-                                if let Some(_match) = query.is_match(line) {
-                                    self.scroll_state.add_match(new_line_idx);
-                                    if self.scroll_state.auto_scroll() {
-                                        self.scroll_state.set_cursor(Some(new_line_idx));
-                                    }
-                                }
-                            }
-                        }
-                    }
-                    Err(err) => {
-                        match err {
-                            std::sync::mpsc::TryRecvError::Empty => {}
-                            std::sync::mpsc::TryRecvError::Disconnected => {
-                                log::warn!("child stdout disconnected");
-                                self.child_exited = true;
-
-                                if let Some(mut handle) = self.child_handle.take() {
-                                    let exit_status = handle.join().unwrap();
-                                    self.pages.write().unwrap().add_line(&format!(
-                                        "Child exited with {} and time took {:?}",
-                                        exit_status,
-                                        self.child_spawn_instant.elapsed()
-                                    ));
-                                }
-                            }
-                        };
-                        break;
+    fn source_changed(&mut self, event: SourceEvent) {
+        let name = &self.source_names[event.source];
+        match event.kind {
+            SourceEventKind::Started => log::info!("{} started", name),
+            SourceEventKind::Exited(status) => {
+                log::warn!("{} exited with {:?}", name, status);
+                if self.input_source == Some(event.source) {
+                    self.input_source = None;
+                }
+            }
+            SourceEventKind::Error(err) => {
+                self.error_timer = ErrorTimer::new(format!("{}: {}", name, err));
+                self.needs_redraw = true;
+            }
+        }
+    }
+
+    /// Joins the ingest thread once every source has closed.
+    fn finish_sources(&mut self) {
+        let Some(ingest_handle) = self.ingest_handle.take() else {
+            return;
+        };
+        log::warn!("all sources closed");
+        let mut writer = ingest_handle.join().unwrap();
+        writer.publish();
+        self.poll_child();
+    }
+
+    /// Recomputes search matches from scratch for the current view.
+    fn refresh_matches(&mut self) {
+        let pages = self.scroll_state.snapshot();
+        if let Some(query) = &self.search_query {
+            self.scroll_state.set_matches(pages.find_all_matches(query));
+        }
+        self.seen_lines = pages.lines_count();
+    }
+
+    /// Keeps search matches in sync with lines published since the last check.
+    fn track_new_lines(&mut self) {
+        let pages = self.scroll_state.snapshot();
+        let first_index = pages.first_index();
+        let lines_count = pages.lines_count();
+
+        self.scroll_state.remove_matches_before(first_index);
+
+        if let Some(query) = &self.search_query {
+            let start = self.seen_lines.max(first_index);
+            let mut it = pages.iter();
+            it.fast_skip(start - first_index);
+            for (i, line) in it.enumerate() {
+                if query.is_match(line).is_some() {
+                    let new_line_idx = start + i;
+                    self.scroll_state.add_match(new_line_idx);
+                    if self.scroll_state.auto_scroll() {
+                        self.scroll_state.set_cursor(Some(new_line_idx));
                     }
                 }
             }
         }
+
+        self.seen_lines = lines_count;
+    }
+}
+
+/// Parses `N` or `BEFORE,AFTER` context line counts.
+fn parse_context(s: &str) -> Option<(usize, usize)> {
+    match s.split_once(',') {
+        Some((before, after)) => Some((before.trim().parse().ok()?, after.trim().parse().ok()?)),
+        None => {
+            let n = s.trim().parse().ok()?;
+            Some((n, n))
+        }
     }
 }
+
+/// Forwards terminal events to the main loop from a blocking reader thread.
+fn spawn_terminal_events(tx: Sender<AppEvent>) {
+    std::thread::spawn(move || loop {
+        match crossterm::event::read() {
+            Ok(event) => {
+                if tx.send(AppEvent::Terminal(event)).is_err() {
+                    break;
+                }
+            }
+            Err(err) => {
+                log::error!("failed to read terminal event {}", err);
+                break;
+            }
+        }
+    });
+}
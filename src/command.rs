@@ -1,10 +1,28 @@
-use std::ops::Range;
+use std::{ops::Range, sync::Arc};
 
 use ratatui::widgets::{Block, Paragraph, Widget};
 use regex::Regex;
 
+use crate::{format::FormatSet, query::Query, timerange::TimeRange};
+
 pub trait Matcher: Sync {
     fn is_match(&self, s: &str) -> Option<Range<usize>>;
+
+    /// Like `is_match` for a line that arrived at `time`, in milliseconds since
+    /// the epoch. Only matchers that look at time need it.
+    fn is_match_at(&self, s: &str, _time: Option<u64>) -> Option<Range<usize>> {
+        self.is_match(s)
+    }
+
+    /// Every non-empty range to highlight in `s`, in order and not overlapping.
+    ///
+    /// Empty if `s` doesn't match. Defaults to the single range of `is_match`.
+    fn match_ranges(&self, s: &str) -> Vec<Range<usize>> {
+        self.is_match(s)
+            .filter(|range| !range.is_empty())
+            .into_iter()
+            .collect()
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -26,8 +44,99 @@ impl Matcher for SearchPattern {
             }
         }
     }
+
+    fn match_ranges(&self, s: &str) -> Vec<Range<usize>> {
+        match self {
+            SearchPattern::Regex(regexp) => regexp
+                .find_iter(s)
+                .filter(|mat| !mat.is_empty())
+                .map(|mat| mat.range())
+                .collect(),
+            SearchPattern::Substring(substr) if substr.is_empty() => Vec::new(),
+            SearchPattern::Substring(substr) => s
+                .match_indices(substr.as_str())
+                .map(|(start, _)| start..start + substr.len())
+                .collect(),
+        }
+    }
+}
+
+impl SearchPattern {
+    /// Compiles `text` with `options`, as a regex if `regex` is set.
+    ///
+    /// Case sensitive literal text without whole word matching stays a plain
+    /// substring search, anything else becomes a regex with the options inlined.
+    pub fn new(text: &str, regex: bool, options: MatchOptions) -> Result<Self, PatternError> {
+        let ignore_case = match options.case {
+            CaseMode::Sensitive => false,
+            CaseMode::Insensitive => true,
+            CaseMode::Smart => !text.chars().any(char::is_uppercase),
+        };
+        let literal = !regex || options.literal;
+        if literal && !ignore_case && !options.whole_word {
+            return Ok(SearchPattern::Substring(text.to_string()));
+        }
+
+        let mut pattern = if literal {
+            regex::escape(text)
+        } else {
+            text.to_string()
+        };
+        if options.whole_word {
+            pattern = format!(r"\b(?:{})\b", pattern);
+        }
+        if ignore_case {
+            pattern = format!("(?i){}", pattern);
+        }
+        match Regex::new(&pattern) {
+            Ok(regex) => Ok(SearchPattern::Regex(regex)),
+            // Locate the problem in what was typed rather than in the wrapped pattern
+            Err(err) if literal => Err(PatternError::from_regex(&pattern, err)),
+            Err(err) => Err(PatternError::from_regex(text, err)),
+        }
+    }
+}
+
+/// Why a pattern failed to compile, `position` is a char offset into the pattern.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PatternError {
+    pub message: String,
+    pub position: Option<usize>,
+}
+
+impl PatternError {
+    fn from_regex(pattern: &str, err: regex::Error) -> Self {
+        let (message, span) = match regex_syntax::Parser::new().parse(pattern) {
+            Err(regex_syntax::Error::Parse(err)) => (err.kind().to_string(), *err.span()),
+            Err(regex_syntax::Error::Translate(err)) => (err.kind().to_string(), *err.span()),
+            // Not a syntax problem, e.g. the compiled regex is too big
+            _ => {
+                let message = err.to_string();
+                let message = message.lines().rfind(|line| !line.is_empty()).unwrap_or("");
+                return Self {
+                    message: message.trim_start_matches("error: ").to_string(),
+                    position: None,
+                };
+            }
+        };
+        Self {
+            message,
+            position: Some(span.start.column - 1),
+        }
+    }
 }
 
+impl std::fmt::Display for PatternError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.position {
+            Some(position) => write!(f, "{} at column {}", self.message, position + 1),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+impl std::error::Error for PatternError {}
+
 impl std::fmt::Display for SearchPattern {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -37,6 +146,85 @@ impl std::fmt::Display for SearchPattern {
     }
 }
 
+/// How letter case is treated when matching.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaseMode {
+    #[default]
+    Sensitive,
+    Insensitive,
+    /// Insensitive unless the pattern contains an uppercase letter
+    Smart,
+}
+
+/// Modifiers applied to every pattern of a command.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MatchOptions {
+    pub case: CaseMode,
+    /// Only match at word boundaries
+    pub whole_word: bool,
+    /// Treat regexes as plain text
+    pub literal: bool,
+}
+
+impl MatchOptions {
+    pub fn cycle_case(&mut self) {
+        self.case = match self.case {
+            CaseMode::Sensitive => CaseMode::Insensitive,
+            CaseMode::Insensitive => CaseMode::Smart,
+            CaseMode::Smart => CaseMode::Sensitive,
+        };
+    }
+
+    /// Compact form for storage, one letter per modifier.
+    pub fn flags(&self) -> String {
+        let mut flags = String::new();
+        match self.case {
+            CaseMode::Sensitive => {}
+            CaseMode::Insensitive => flags.push('i'),
+            CaseMode::Smart => flags.push('s'),
+        }
+        if self.whole_word {
+            flags.push('w');
+        }
+        if self.literal {
+            flags.push('l');
+        }
+        flags
+    }
+
+    pub fn from_flags(flags: &str) -> Self {
+        let mut options = Self::default();
+        for flag in flags.chars() {
+            match flag {
+                'i' => options.case = CaseMode::Insensitive,
+                's' => options.case = CaseMode::Smart,
+                'w' => options.whole_word = true,
+                'l' => options.literal = true,
+                _ => {}
+            }
+        }
+        options
+    }
+}
+
+impl std::fmt::Display for MatchOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut labels = Vec::new();
+        match self.case {
+            CaseMode::Sensitive => {}
+            CaseMode::Insensitive => labels.push("ignore case"),
+            CaseMode::Smart => labels.push("smart case"),
+        }
+        if self.whole_word {
+            labels.push("word");
+        }
+        if self.literal {
+            labels.push("literal");
+        }
+        write!(f, "{}", labels.join(", "))
+    }
+}
+
 #[derive(Default, Debug, Clone, PartialEq)]
 pub enum CommandType {
     #[default]
@@ -46,12 +234,20 @@ pub enum CommandType {
     Regex,
     JumpTo,
     Filter,
+    Highlight,
+    Context,
+    Columns,
+    TimeRange,
 }
 
 #[derive(Default, Debug)]
 pub struct CommandBuilder {
     pub cmd_type: CommandType,
     pub cmd: String,
+    /// Kept across commands until toggled again
+    pub options: MatchOptions,
+    /// Formats filters read fields and times with
+    pub formats: Option<Arc<FormatSet>>,
 }
 
 impl CommandBuilder {
@@ -106,11 +302,28 @@ impl<'a> Widget for FilterTitleWidget<'a> {
             CommandType::Ignore => "Ignore",
             CommandType::Search => "Search",
             CommandType::Regex => "Regex",
-            CommandType::JumpTo => "JumpTo",
+            CommandType::JumpTo => "Jump to LINE, +N, -N, N%, @TIME",
             CommandType::Filter => "Filter",
+            CommandType::Highlight => "Highlight PATTERN=COLOR",
+            CommandType::Context => "Context lines, N or BEFORE,AFTER",
+            CommandType::Columns => "Columns FIELD[:WIDTH],..., empty for whole lines",
+            CommandType::TimeRange => "Time range: since T, until T, between T and T, last N",
         };
         if !prefix.is_empty() {
             command.push_str(prefix);
+            let options = self.cmd.options.to_string();
+            let uses_options = !matches!(
+                self.cmd.cmd_type,
+                CommandType::JumpTo
+                    | CommandType::Context
+                    | CommandType::Columns
+                    | CommandType::TimeRange
+            );
+            if !options.is_empty() && uses_options {
+                command.push_str(" [");
+                command.push_str(&options);
+                command.push(']');
+            }
             command.push_str(": ");
             command.push_str(&self.cmd.cmd);
         }
@@ -123,21 +336,74 @@ impl<'a> Widget for FilterTitleWidget<'a> {
 
 impl CommandBuilder {
     pub fn build(&self) -> Option<Command> {
+        self.try_build().ok().flatten()
+    }
+
+    /// Like `build`, with the reason the command is invalid.
+    pub fn try_build(&self) -> Result<Option<Command>, String> {
+        let command = self.try_build_command()?;
+        Ok(command.map(|command| command.with_formats(self.formats.clone())))
+    }
+
+    fn try_build_command(&self) -> Result<Option<Command>, String> {
+        let pattern = |regex| {
+            SearchPattern::new(&self.cmd, regex, self.options).map_err(|err| err.to_string())
+        };
         match self.cmd_type {
-            CommandType::Ignore => {
-                Some(Command::Ignore(SearchPattern::Substring(self.cmd.clone())))
+            CommandType::Ignore => Ok(Some(Command::Ignore(pattern(false)?))),
+            CommandType::Search => Ok(Some(Command::SearchFor(pattern(false)?))),
+            CommandType::Regex => Ok(Some(Command::SearchFor(pattern(true)?))),
+            CommandType::Filter if self.cmd.trim().is_empty() => Ok(None),
+            CommandType::Filter => {
+                let query =
+                    Query::parse_with(&self.cmd, self.options).map_err(|err| err.to_string())?;
+                Ok(Some(Command::Filter(query)))
             }
-            CommandType::Search => Some(Command::SearchFor(SearchPattern::Substring(
-                self.cmd.clone(),
-            ))),
-            CommandType::Regex => {
-                let regex = Regex::new(&self.cmd).ok()?;
-                Some(Command::SearchFor(SearchPattern::Regex(regex)))
+            CommandType::TimeRange => {
+                let range = TimeRange::parse(&self.cmd, crate::pages::now_millis())?;
+                Ok(Some(Command::TimeRange(range)))
             }
-            CommandType::Filter => Some(Command::SearchFor(SearchPattern::Substring(
-                self.cmd.clone(),
-            ))),
-            _ => None,
+            _ => Ok(None),
+        }
+    }
+}
+
+/// Where `:` jumps to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JumpTarget {
+    /// A line number as shown in the gutter, `120`
+    Line(usize),
+    /// Lines down or up from the cursor, `+100` or `-50`
+    Relative(i64),
+    /// A position in the buffer, `50%`
+    Percent(f64),
+    /// A share of the buffer down or up from the cursor, `-10%`
+    RelativePercent(f64),
+    /// The first line at or after a time, `@14:32`, see [`crate::timerange`]
+    Time(u64),
+}
+
+impl JumpTarget {
+    /// Parses `s`, with relative times counted back from `now`.
+    pub fn parse(s: &str, now: u64) -> Result<Self, String> {
+        let s = s.trim();
+        if let Some(time) = s.strip_prefix('@') {
+            return crate::timerange::parse_time(time, now).map(JumpTarget::Time);
+        }
+        let relative = s.starts_with(['+', '-']);
+        let invalid = || format!("unable to parse '{}', expected LINE, +N, N% or @TIME", s);
+        if let Some(percent) = s.strip_suffix('%') {
+            let percent: f64 = percent.parse().map_err(|_| invalid())?;
+            return Ok(if relative {
+                JumpTarget::RelativePercent(percent)
+            } else {
+                JumpTarget::Percent(percent)
+            });
+        }
+        if relative {
+            s.parse().map(JumpTarget::Relative).map_err(|_| invalid())
+        } else {
+            s.parse().map(JumpTarget::Line).map_err(|_| invalid())
         }
     }
 }
@@ -146,6 +412,20 @@ impl CommandBuilder {
 pub enum Command {
     Ignore(SearchPattern),
     SearchFor(SearchPattern),
+    Filter(Query),
+    /// Keeps the lines within a time range
+    TimeRange(TimeRange),
+}
+
+impl Command {
+    /// Lets filters read fields and times with `formats`, see [`crate::format`].
+    pub fn with_formats(self, formats: Option<Arc<FormatSet>>) -> Self {
+        match self {
+            Command::Filter(query) => Command::Filter(query.with_formats(formats)),
+            Command::TimeRange(range) => Command::TimeRange(range.with_formats(formats)),
+            command => command,
+        }
+    }
 }
 
 impl std::fmt::Display for Command {
@@ -153,6 +433,8 @@ impl std::fmt::Display for Command {
         match self {
             Command::Ignore(p) => write!(f, "Ignore:{}", p),
             Command::SearchFor(p) => write!(f, "{}", p),
+            Command::Filter(q) => write!(f, "{}", q),
+            Command::TimeRange(range) => write!(f, "{}", range),
         }
     }
 }
@@ -162,6 +444,85 @@ impl Matcher for Command {
         match self {
             Command::Ignore(search_pattern) => search_pattern.is_match(s),
             Command::SearchFor(search_pattern) => search_pattern.is_match(s),
+            Command::Filter(query) => query.is_match(s),
+            Command::TimeRange(range) => range.is_match(s),
+        }
+    }
+
+    fn is_match_at(&self, s: &str, time: Option<u64>) -> Option<Range<usize>> {
+        match self {
+            Command::TimeRange(range) => range.is_match_at(s, time),
+            command => command.is_match(s),
+        }
+    }
+
+    fn match_ranges(&self, s: &str) -> Vec<Range<usize>> {
+        match self {
+            Command::Ignore(search_pattern) => search_pattern.match_ranges(s),
+            Command::SearchFor(search_pattern) => search_pattern.match_ranges(s),
+            Command::Filter(query) => query.match_ranges(s),
+            Command::TimeRange(range) => range.match_ranges(s),
+        }
+    }
+}
+
+/// Sorts `ranges` and joins the ones that overlap.
+pub(crate) fn merge_ranges(mut ranges: Vec<Range<usize>>) -> Vec<Range<usize>> {
+    ranges.sort_by_key(|range| range.start);
+    let mut merged: Vec<Range<usize>> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start < last.end => last.end = last.end.max(range.end),
+            _ => merged.push(range),
         }
     }
+    merged
+}
+
+#[test]
+fn test_search_pattern_options() {
+    let pattern =
+        |text, regex, flags| SearchPattern::new(text, regex, MatchOptions::from_flags(flags));
+
+    // Plain text keeps the substring fast path
+    assert!(matches!(
+        pattern("Err", false, ""),
+        Ok(SearchPattern::Substring(_))
+    ));
+
+    let ignore_case = pattern("err", false, "i").unwrap();
+    assert_eq!(ignore_case.is_match("an ERROR"), Some(3..6));
+
+    let smart = pattern("err", false, "s").unwrap();
+    assert_eq!(smart.is_match("ERR"), Some(0..3));
+    let smart = pattern("Err", false, "s").unwrap();
+    assert!(matches!(smart, SearchPattern::Substring(_)));
+    assert_eq!(smart.is_match("ERR"), None);
+
+    let word = pattern("err", false, "w").unwrap();
+    assert_eq!(word.is_match("stderr err"), Some(7..10));
+
+    let literal = pattern("a.c", true, "l").unwrap();
+    assert_eq!(literal.is_match("abc a.c"), Some(4..7));
+    assert_eq!(ignore_case.match_ranges("err ERR eRr"), [0..3, 4..7, 8..11]);
+    assert_eq!(
+        pattern("ab", false, "").unwrap().match_ranges("abab"),
+        [0..2, 2..4]
+    );
+    let err = pattern(r"ab(c", true, "iw").unwrap_err();
+    assert_eq!(err.to_string(), "unclosed group at column 3");
+}
+
+#[test]
+fn test_jump_target() {
+    let parse = |s| JumpTarget::parse(s, 1_000_000);
+    assert_eq!(parse("120"), Ok(JumpTarget::Line(120)));
+    assert_eq!(parse("+100"), Ok(JumpTarget::Relative(100)));
+    assert_eq!(parse("-50"), Ok(JumpTarget::Relative(-50)));
+    assert_eq!(parse("50%"), Ok(JumpTarget::Percent(50.0)));
+    assert_eq!(parse("-50%"), Ok(JumpTarget::RelativePercent(-50.0)));
+    assert_eq!(parse("@10s ago"), Ok(JumpTarget::Time(990_000)));
+    assert!(parse("ten").is_err());
+    assert!(parse("@soon").is_err());
+    assert!(parse("@1é ago").is_err());
 }
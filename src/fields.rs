@@ -0,0 +1,353 @@
+//! Fields of structured log lines.
+//!
+//! A line holding one JSON object exposes its members as fields, nested
+//! objects flattened into dotted keys (`user.id`). Otherwise `key=value` pairs
+//! in logfmt style, `key2="quoted value"`, are its fields. Filters compare fields with
+//! `key=value`, `key>value` and so on, see [`crate::query`], and [`Columns`]
+//! shows chosen fields instead of the whole line.
+
+use std::ops::Range;
+
+use serde_json::Value;
+
+use crate::format::FormatSet;
+
+/// A field of a line, with its value as text.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Field {
+    pub key: String,
+    pub value: String,
+    /// Where the value is in the line, if it could be located
+    pub range: Option<Range<usize>>,
+    /// Where the key is in the line, for logfmt
+    pub key_range: Option<Range<usize>>,
+}
+
+/// Fields of `line`, `None` if it isn't structured.
+///
+/// The format of the line's source in `formats` comes first, see [`crate::format`].
+pub fn parse_fields(line: &str, formats: Option<&FormatSet>) -> Option<Vec<Field>> {
+    formats
+        .and_then(|formats| formats.fields(line))
+        .or_else(|| parse_json(line))
+        .or_else(|| parse_logfmt(line))
+}
+
+/// The `key=value` pairs of `line`, `None` unless it has at least two, or one
+/// and nothing else.
+///
+/// Values may be quoted, with `\"` and `\\` escapes. Words that aren't pairs,
+/// like a leading timestamp, are skipped.
+pub fn parse_logfmt(line: &str) -> Option<Vec<Field>> {
+    let bytes = line.as_bytes();
+    let mut fields = Vec::new();
+    let mut words = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i].is_ascii_whitespace() {
+            i += 1;
+            continue;
+        }
+        words += 1;
+        let key_start = i;
+        while i < bytes.len() && is_key_byte(bytes[i], i == key_start) {
+            i += 1;
+        }
+        if i == key_start || bytes.get(i) != Some(&b'=') {
+            // Not a pair, skip the rest of the word
+            while i < bytes.len() && !bytes[i].is_ascii_whitespace() {
+                i += 1;
+            }
+            continue;
+        }
+        let key_range = key_start..i;
+        i += 1;
+        let (value, range) = if bytes.get(i) == Some(&b'"') {
+            let start = i + 1;
+            let mut value = String::new();
+            i = start;
+            let mut closed = false;
+            while i < bytes.len() {
+                match bytes[i] {
+                    b'\\' if matches!(bytes.get(i + 1), Some(b'"' | b'\\')) => {
+                        value.push(bytes[i + 1] as char);
+                        i += 2;
+                    }
+                    b'"' => {
+                        closed = true;
+                        break;
+                    }
+                    _ => {
+                        let c = line[i..].chars().next()?;
+                        value.push(c);
+                        i += c.len_utf8();
+                    }
+                }
+            }
+            if !closed {
+                return None;
+            }
+            let range = start..i;
+            i += 1;
+            (value, range)
+        } else {
+            let start = i;
+            while i < bytes.len() && !bytes[i].is_ascii_whitespace() {
+                i += 1;
+            }
+            (line[start..i].to_string(), start..i)
+        };
+        fields.push(Field {
+            key: line[key_range.clone()].to_string(),
+            value,
+            range: Some(range),
+            key_range: Some(key_range),
+        });
+    }
+    let structured = fields.len() >= 2 || (fields.len() == 1 && words == 1);
+    structured.then_some(fields)
+}
+
+fn is_key_byte(b: u8, first: bool) -> bool {
+    match b {
+        b'a'..=b'z' | b'A'..=b'Z' | b'_' => true,
+        b'0'..=b'9' | b'.' | b'-' | b'/' | b'@' => !first,
+        _ => false,
+    }
+}
+
+fn parse_json(line: &str) -> Option<Vec<Field>> {
+    let trimmed = line.trim();
+    if !trimmed.starts_with('{') || !trimmed.ends_with('}') {
+        return None;
+    }
+    let Ok(Value::Object(object)) = serde_json::from_str(trimmed) else {
+        return None;
+    };
+    let mut fields = Vec::new();
+    let scope = Some(0..line.len());
+    for (key, value) in &object {
+        flatten_json(line, key, key, value, scope.clone(), &mut fields);
+    }
+    Some(fields)
+}
+
+/// Adds `value` as the field `key`, or its members if it is an object. `scope`
+/// is where the object holding it is in the line, if it could be located.
+fn flatten_json(
+    line: &str,
+    key: &str,
+    name: &str,
+    value: &Value,
+    scope: Option<Range<usize>>,
+    fields: &mut Vec<Field>,
+) {
+    let text = match value {
+        Value::Object(object) => {
+            // Members are only looked for inside their own object
+            let inner = scope
+                .and_then(|scope| {
+                    locate_json_member(line, scope, name, |rest| rest.starts_with('{'))
+                })
+                .and_then(|start| Some(start..json_object_end(line, start)?));
+            for (name, value) in object {
+                let key = format!("{}.{}", key, name);
+                flatten_json(line, &key, name, value, inner.clone(), fields);
+            }
+            return;
+        }
+        Value::String(s) => s.clone(),
+        value => value.to_string(),
+    };
+    let raw = value.to_string();
+    let range = scope
+        .and_then(|scope| locate_json_member(line, scope, name, |rest| rest.starts_with(&raw)))
+        .map(|start| match value {
+            Value::String(_) => start + 1..start + raw.len() - 1,
+            _ => start..start + raw.len(),
+        });
+    fields.push(Field {
+        key: key.to_string(),
+        value: text,
+        range,
+        key_range: None,
+    });
+}
+
+/// Finds `"name": value` within `scope` of `line` where the value passes
+/// `is_value`, and returns where the value starts. `None` unless there is
+/// exactly one, as a nested object may hold the same member. Values written
+/// differently from the way they serialize back aren't found.
+fn locate_json_member(
+    line: &str,
+    scope: Range<usize>,
+    name: &str,
+    is_value: impl Fn(&str) -> bool,
+) -> Option<usize> {
+    let needle = format!("\"{}\"", name);
+    let mut found = None;
+    for (pos, _) in line[scope.clone()].match_indices(&needle) {
+        let rest = line[scope.start + pos + needle.len()..scope.end].trim_start();
+        let Some(rest) = rest.strip_prefix(':') else {
+            continue;
+        };
+        let rest = rest.trim_start();
+        if is_value(rest) {
+            if found.is_some() {
+                return None;
+            }
+            found = Some(scope.end - rest.len());
+        }
+    }
+    found
+}
+
+/// End of the JSON object starting with the `{` at `start`, past its `}`.
+fn json_object_end(line: &str, start: usize) -> Option<usize> {
+    let mut depth = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, b) in line.bytes().enumerate().skip(start) {
+        if in_string {
+            match b {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match b {
+            b'"' => in_string = true,
+            b'{' => depth += 1,
+            b'}' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i + 1);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Chosen fields shown as columns in place of structured lines.
+///
+/// Written as `key[:width],...`, e.g. `time,level:5,msg`. Every column but the
+/// last is padded to its width, `COLUMN_WIDTH` unless given.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Columns {
+    columns: Vec<(String, usize)>,
+}
+
+const COLUMN_WIDTH: usize = 12;
+
+impl Columns {
+    /// `line` with only the chosen fields, `None` if it isn't structured.
+    pub fn project(&self, line: &str, formats: Option<&FormatSet>) -> Option<String> {
+        let fields = parse_fields(line, formats)?;
+        let mut text = String::new();
+        for (i, (key, width)) in self.columns.iter().enumerate() {
+            let value = fields
+                .iter()
+                .find(|field| &field.key == key)
+                .map_or("-", |field| field.value.as_str());
+            // A value spanning lines would break the columns
+            let value = value.replace('\n', " ");
+            if i + 1 < self.columns.len() {
+                text.push_str(&format!("{:<width$} ", value, width = width));
+            } else {
+                text.push_str(&value);
+            }
+        }
+        Some(text)
+    }
+}
+
+impl std::str::FromStr for Columns {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let mut columns = Vec::new();
+        for column in spec.split(',').map(str::trim).filter(|c| !c.is_empty()) {
+            let column = match column.split_once(':') {
+                Some((key, width)) => {
+                    let width = width
+                        .parse()
+                        .map_err(|_| format!("invalid width '{}' for {}", width, key))?;
+                    (key.to_string(), width)
+                }
+                None => (column.to_string(), COLUMN_WIDTH),
+            };
+            columns.push(column);
+        }
+        if columns.is_empty() {
+            return Err("expected FIELD[:WIDTH],...".to_string());
+        }
+        Ok(Self { columns })
+    }
+}
+
+impl std::fmt::Display for Columns {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let columns: Vec<String> = self
+            .columns
+            .iter()
+            .map(|(key, width)| match *width {
+                COLUMN_WIDTH => key.clone(),
+                width => format!("{}:{}", key, width),
+            })
+            .collect();
+        write!(f, "{}", columns.join(","))
+    }
+}
+
+#[test]
+fn test_json_fields() {
+    let line = r#"{"level":"error", "latency_ms": 512, "user": {"id": 42}, "tags": ["a"]}"#;
+    let fields = parse_fields(line, None).unwrap();
+    let field = |key: &str| fields.iter().find(|f| f.key == key).unwrap();
+    assert_eq!(field("level").value, "error");
+    assert_eq!(&line[field("level").range.clone().unwrap()], "error");
+    assert_eq!(&line[field("latency_ms").range.clone().unwrap()], "512");
+    assert_eq!(field("user.id").value, "42");
+    assert_eq!(&line[field("user.id").range.clone().unwrap()], "42");
+    assert_eq!(field("tags").value, r#"["a"]"#);
+
+    // Members of different objects sharing a name and value
+    let nested = parse_fields(r#"{"a":{"id":1},"b":{"id":1}}"#, None).unwrap();
+    assert_eq!(nested[0].range, Some(11..12));
+    assert_eq!(nested[1].range, Some(24..25));
+
+    assert_eq!(parse_fields("plain text", None), None);
+    assert_eq!(parse_fields("{not json}", None), None);
+
+    let columns: Columns = "level:6,user.id,msg".parse().unwrap();
+    assert_eq!(columns.to_string(), "level:6,user.id,msg");
+    assert_eq!(
+        columns.project(line, None).unwrap(),
+        format!("error  {:<12} -", 42)
+    );
+    assert_eq!(columns.project("plain text", None), None);
+    assert!("level:x".parse::<Columns>().is_err());
+}
+
+#[test]
+fn test_logfmt_fields() {
+    let line = r#"at=info method=GET path="/a \"b\"" status=200 empty= connect=1ms"#;
+    let fields = parse_fields(line, None).unwrap();
+    let keys: Vec<_> = fields.iter().map(|f| f.key.as_str()).collect();
+    assert_eq!(keys, ["at", "method", "path", "status", "empty", "connect"]);
+    assert_eq!(fields[2].value, r#"/a "b""#);
+    assert_eq!(&line[fields[2].range.clone().unwrap()], r#"/a \"b\""#);
+    assert_eq!(&line[fields[3].key_range.clone().unwrap()], "status");
+    assert_eq!(fields[4].value, "");
+
+    // Words around the pairs are skipped
+    let fields = parse_fields("2024-01-01 app[web.1]: level=warn msg=slow", None).unwrap();
+    assert_eq!(fields.len(), 2);
+    assert_eq!(parse_fields("level=warn", None), parse_logfmt("level=warn"));
+    assert_eq!(parse_fields("set x=1 then", None), None);
+    assert_eq!(parse_fields(r#"a=1 b="unterminated"#, None), None);
+}
@@ -0,0 +1,269 @@
+use std::ops::Range;
+
+use crate::{
+    command::{merge_ranges, Command, Matcher},
+    level::LevelFilter,
+};
+
+/// One step of narrowing down the lines, which can be switched off without losing it.
+#[derive(Debug, Clone)]
+pub struct FilterLayer {
+    pub command: Command,
+    pub enabled: bool,
+}
+
+impl FilterLayer {
+    pub fn new(command: Command) -> Self {
+        Self {
+            command,
+            enabled: true,
+        }
+    }
+
+    /// The pattern as it was typed.
+    pub fn text(&self) -> String {
+        match &self.command {
+            Command::Ignore(pattern) => pattern.to_string(),
+            command => command.to_string(),
+        }
+    }
+
+    /// Ignore layers hide the lines they match, any other layer keeps only those.
+    pub fn is_exclude(&self) -> bool {
+        matches!(self.command, Command::Ignore(_))
+    }
+}
+
+impl std::fmt::Display for FilterLayer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_exclude() {
+            write!(f, "-{}", self.text())
+        } else {
+            write!(f, "{}", self.text())
+        }
+    }
+}
+
+/// Filter layers applied on top of each other, a line is shown if it passes
+/// every enabled layer.
+///
+/// A preview layer can sit on top while a filter is being typed, it applies
+/// like the others but isn't listed or saved until it is pushed. A minimum
+/// level applies on top of every layer.
+#[derive(Debug, Clone, Default)]
+pub struct FilterStack {
+    layers: Vec<FilterLayer>,
+    preview: Option<FilterLayer>,
+    level: Option<LevelFilter>,
+}
+
+impl FilterStack {
+    pub fn layers(&self) -> &[FilterLayer] {
+        &self.layers
+    }
+
+    pub fn len(&self) -> usize {
+        self.layers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.layers.is_empty()
+    }
+
+    /// Whether any layer is enabled, otherwise every line passes
+    pub fn is_active(&self) -> bool {
+        self.level.is_some() || self.enabled_layers().next().is_some()
+    }
+
+    pub fn level(&self) -> Option<&LevelFilter> {
+        self.level.as_ref()
+    }
+
+    pub fn set_level(&mut self, level: Option<LevelFilter>) {
+        self.level = level;
+    }
+
+    pub fn preview(&self) -> Option<&FilterLayer> {
+        self.preview.as_ref()
+    }
+
+    pub fn set_preview(&mut self, layer: Option<FilterLayer>) {
+        self.preview = layer;
+    }
+
+    fn enabled_layers(&self) -> impl Iterator<Item = &FilterLayer> {
+        self.layers
+            .iter()
+            .chain(&self.preview)
+            .filter(|layer| layer.enabled)
+    }
+
+    pub fn push(&mut self, layer: FilterLayer) {
+        self.layers.push(layer);
+    }
+
+    pub fn toggle(&mut self, idx: usize) {
+        if let Some(layer) = self.layers.get_mut(idx) {
+            layer.enabled = !layer.enabled;
+        }
+    }
+
+    pub fn remove(&mut self, idx: usize) {
+        if idx < self.layers.len() {
+            self.layers.remove(idx);
+        }
+    }
+
+    /// Swaps layer `idx` with the one above it, returns its new index.
+    pub fn move_up(&mut self, idx: usize) -> usize {
+        if idx == 0 || idx >= self.layers.len() {
+            return idx;
+        }
+        self.layers.swap(idx, idx - 1);
+        idx - 1
+    }
+
+    /// Swaps layer `idx` with the one below it, returns its new index.
+    pub fn move_down(&mut self, idx: usize) -> usize {
+        if idx + 1 >= self.layers.len() {
+            return idx;
+        }
+        self.layers.swap(idx, idx + 1);
+        idx + 1
+    }
+
+    pub fn clear(&mut self) {
+        self.layers.clear();
+        self.preview = None;
+        self.level = None;
+    }
+
+    /// One line description of the layers, `None` if there are none.
+    pub fn summary(&self) -> Option<String> {
+        if self.layers.is_empty() && self.level.is_none() {
+            return None;
+        }
+        let mut layers: Vec<String> = self
+            .layers
+            .iter()
+            .map(|layer| {
+                if layer.enabled {
+                    layer.to_string()
+                } else {
+                    format!("{} (off)", layer)
+                }
+            })
+            .collect();
+        if let Some(level) = &self.level {
+            layers.push(format!("level {}+", level.min));
+        }
+        Some(layers.join(" » "))
+    }
+}
+
+impl From<Vec<FilterLayer>> for FilterStack {
+    fn from(layers: Vec<FilterLayer>) -> Self {
+        Self {
+            layers,
+            preview: None,
+            level: None,
+        }
+    }
+}
+
+impl Matcher for FilterStack {
+    /// The highlight is the match of the first enabled include layer, empty if
+    /// there is none.
+    fn is_match(&self, s: &str) -> Option<Range<usize>> {
+        self.is_match_at(s, None)
+    }
+
+    fn is_match_at(&self, s: &str, time: Option<u64>) -> Option<Range<usize>> {
+        if let Some(level) = &self.level {
+            level.is_match(s)?;
+        }
+        let mut highlight = None;
+        for layer in self.enabled_layers() {
+            let range = layer.command.is_match_at(s, time);
+            if layer.is_exclude() {
+                if range.is_some() {
+                    return None;
+                }
+            } else {
+                let range = range?;
+                highlight = highlight.or(Some(range));
+            }
+        }
+        Some(highlight.unwrap_or(0..0))
+    }
+
+    /// Occurrences of every enabled include layer, if the line passes.
+    fn match_ranges(&self, s: &str) -> Vec<Range<usize>> {
+        if self.is_match(s).is_none() {
+            return Vec::new();
+        }
+        let ranges = self
+            .enabled_layers()
+            .filter(|layer| !layer.is_exclude())
+            .flat_map(|layer| layer.command.match_ranges(s))
+            .collect();
+        merge_ranges(ranges)
+    }
+}
+
+#[test]
+fn test_filter_stack_layers() {
+    use crate::command::SearchPattern;
+    use crate::query::Query;
+
+    let mut stack = FilterStack::default();
+    stack.push(FilterLayer::new(Command::Filter(
+        Query::parse("api").unwrap(),
+    )));
+    stack.push(FilterLayer::new(Command::Ignore(SearchPattern::Substring(
+        "healthz".to_string(),
+    ))));
+    stack.push(FilterLayer::new(Command::Filter(
+        Query::parse(r"/ 5\d\d /").unwrap(),
+    )));
+
+    assert_eq!(stack.is_match("GET /api/users 503 "), Some(5..8));
+    assert_eq!(stack.is_match("GET /api/users 200 "), None);
+    assert_eq!(stack.is_match("GET /api/healthz 500 "), None);
+    assert_eq!(
+        stack.match_ranges("GET /api/api 503 "),
+        [5..8, 9..12, 12..17]
+    );
+
+    stack.toggle(2);
+    assert_eq!(stack.is_match("GET /api/users 200 "), Some(5..8));
+    assert_eq!(
+        stack.summary().unwrap(),
+        r"api » -healthz » / 5\d\d / (off)"
+    );
+
+    assert_eq!(stack.move_up(1), 0);
+    stack.remove(0);
+    assert_eq!(stack.is_match("GET /api/healthz 200 "), Some(5..8));
+
+    // A preview narrows the lines without becoming a layer
+    stack.set_preview(Some(FilterLayer::new(Command::Ignore(
+        SearchPattern::Substring("users".to_string()),
+    ))));
+    assert_eq!(stack.is_match("GET /api/users 200 "), None);
+    assert_eq!(stack.len(), 2);
+    stack.set_preview(None);
+    assert_eq!(stack.is_match("GET /api/users 200 "), Some(5..8));
+
+    // A minimum level narrows every layer down further
+    stack.set_level(Some(LevelFilter::new(
+        crate::level::Level::Warn,
+        Default::default(),
+    )));
+    assert_eq!(stack.is_match("GET /api/users 200 "), None);
+    assert_eq!(stack.is_match("WARN GET /api/users 200 "), Some(10..13));
+    assert_eq!(
+        stack.summary().unwrap(),
+        r"api » / 5\d\d / (off) » level warn+"
+    );
+}
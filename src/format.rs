@@ -0,0 +1,305 @@
+//! User-defined log formats.
+//!
+//! Formats are read from a file with one section per format:
+//!
+//! ```text
+//! # nginx access log
+//! [nginx]
+//! regex = ^(?P<host>\S+) \S+ \S+ \[(?P<timestamp>[^\]]+)\] "(?P<msg>[^"]*)" (?P<status>\d+)
+//! timestamp = %d/%b/%Y:%H:%M:%S %z
+//! ```
+//!
+//! Named captures become fields of the line, `timestamp`, `level`, `module` and
+//! `msg` by convention. `timestamp` is a chrono format string, or `epoch` and
+//! `epoch_ms` for seconds and milliseconds since the epoch. Each source uses the
+//! first format that matches one of its first lines.
+
+use std::{path::Path, sync::RwLock};
+
+use anyhow::Context;
+use regex::Regex;
+
+use crate::{fields::Field, source::SourceId};
+
+/// Lines of a source tried against the formats before giving up on it.
+const DETECT_LINES: usize = 100;
+
+/// A format of lines, see the module docs.
+#[derive(Debug, Clone)]
+pub struct LogFormat {
+    pub name: String,
+    pub regex: Regex,
+    /// How the `timestamp` capture is written
+    pub timestamp: Option<String>,
+}
+
+impl LogFormat {
+    /// The named captures of `line`, `None` if the regex doesn't match. Ranges
+    /// are shifted by `offset`.
+    pub fn fields(&self, line: &str, offset: usize) -> Option<Vec<Field>> {
+        let captures = self.regex.captures(line)?;
+        let fields = self
+            .regex
+            .capture_names()
+            .flatten()
+            .filter_map(|name| {
+                let capture = captures.name(name)?;
+                Some(Field {
+                    key: name.to_string(),
+                    value: capture.as_str().to_string(),
+                    range: Some(offset + capture.start()..offset + capture.end()),
+                    key_range: None,
+                })
+            })
+            .collect();
+        Some(fields)
+    }
+}
+
+/// Milliseconds since the epoch of `value`, written as `format`.
+///
+/// Without an offset the time is local, without a date it is today.
+pub fn parse_timestamp(value: &str, format: &str) -> Option<u64> {
+    use chrono::{DateTime, Local, NaiveDateTime, NaiveTime, TimeZone};
+
+    let millis = match format {
+        "epoch" => (value.parse::<f64>().ok()? * 1000.0) as i64,
+        "epoch_ms" => value.parse().ok()?,
+        _ => {
+            if let Ok(time) = DateTime::parse_from_str(value, format) {
+                time.timestamp_millis()
+            } else if let Ok(time) = NaiveDateTime::parse_from_str(value, format) {
+                Local
+                    .from_local_datetime(&time)
+                    .earliest()?
+                    .timestamp_millis()
+            } else {
+                let time = NaiveTime::parse_from_str(value, format).ok()?;
+                let time = Local::now().date_naive().and_time(time);
+                Local
+                    .from_local_datetime(&time)
+                    .earliest()?
+                    .timestamp_millis()
+            }
+        }
+    };
+    u64::try_from(millis).ok()
+}
+
+/// Parses the sections of a formats file, see the module docs.
+pub fn parse_formats(text: &str) -> Result<Vec<LogFormat>, String> {
+    let mut sections: Vec<(usize, String, Option<String>, Option<String>)> = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let number = i + 1;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            sections.push((number, name.trim().to_string(), None, None));
+            continue;
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            return Err(format!("line {}: expected KEY = VALUE", number));
+        };
+        let Some((_, _, regex, timestamp)) = sections.last_mut() else {
+            return Err(format!(
+                "line {}: {} outside of a [format]",
+                number,
+                key.trim()
+            ));
+        };
+        let value = Some(value.trim().to_string());
+        match key.trim() {
+            "regex" => *regex = value,
+            "timestamp" => *timestamp = value,
+            key => return Err(format!("line {}: unknown key '{}'", number, key)),
+        }
+    }
+
+    sections
+        .into_iter()
+        .map(|(number, name, regex, timestamp)| {
+            let Some(regex) = regex else {
+                return Err(format!("line {}: [{}] has no regex", number, name));
+            };
+            let regex = Regex::new(&regex)
+                .map_err(|err| format!("line {}: invalid regex for [{}], {}", number, name, err))?;
+            Ok(LogFormat {
+                name,
+                regex,
+                timestamp,
+            })
+        })
+        .collect()
+}
+
+pub fn load_formats(path: &Path) -> anyhow::Result<Vec<LogFormat>> {
+    let text =
+        std::fs::read_to_string(path).with_context(|| format!("can't read {}", path.display()))?;
+    parse_formats(&text).map_err(|err| anyhow::anyhow!("{}: {}", path.display(), err))
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Choice {
+    /// Lines tried so far without a match
+    Pending(usize),
+    Format(usize),
+    Unknown,
+}
+
+/// The formats and which one each source uses.
+///
+/// Shared as an `Arc` with what parses lines: the [`crate::level::LevelDetector`]
+/// the ingest thread detects formats with, filters and the scroll widget.
+#[derive(Debug)]
+pub struct FormatSet {
+    formats: Vec<LogFormat>,
+    /// What lines of each source start with once appended, see
+    /// [`crate::ingest::source_prefixes`]
+    prefixes: Vec<String>,
+    chosen: RwLock<Vec<Choice>>,
+}
+
+impl FormatSet {
+    pub fn new(formats: Vec<LogFormat>, prefixes: Vec<String>) -> Self {
+        let chosen = RwLock::new(vec![Choice::Pending(0); prefixes.len()]);
+        Self {
+            formats,
+            prefixes,
+            chosen,
+        }
+    }
+
+    /// Picks the format of `source` if `line` is the first of it to match one.
+    pub fn detect(&self, source: SourceId, line: &str) {
+        let pending = matches!(
+            self.chosen.read().unwrap().get(source),
+            Some(Choice::Pending(_))
+        );
+        if !pending {
+            return;
+        }
+        let found = self.formats.iter().position(|f| f.regex.is_match(line));
+        let mut chosen = self.chosen.write().unwrap();
+        chosen[source] = match (found, chosen[source]) {
+            (Some(format), _) => {
+                log::info!(
+                    "source {} uses format {}",
+                    source,
+                    self.formats[format].name
+                );
+                Choice::Format(format)
+            }
+            (None, Choice::Pending(tried)) if tried + 1 < DETECT_LINES => {
+                Choice::Pending(tried + 1)
+            }
+            (None, _) => Choice::Unknown,
+        };
+    }
+
+    /// Name of the format `source` uses, once picked.
+    pub fn chosen(&self, source: SourceId) -> Option<&str> {
+        match self.chosen.read().unwrap().get(source) {
+            Some(Choice::Format(format)) => Some(&self.formats[*format].name),
+            _ => None,
+        }
+    }
+
+    /// The format of the source `line` came from and where its own text starts.
+    fn format_of<'l>(&self, line: &'l str) -> Option<(&LogFormat, &'l str, usize)> {
+        let chosen = self.chosen.read().unwrap();
+        chosen
+            .iter()
+            .zip(&self.prefixes)
+            .find_map(|(choice, prefix)| match choice {
+                Choice::Format(format) => {
+                    let rest = line.strip_prefix(prefix.as_str())?;
+                    Some((&self.formats[*format], rest, prefix.len()))
+                }
+                _ => None,
+            })
+    }
+
+    /// Fields of `line` by the format of its source.
+    pub fn fields(&self, line: &str) -> Option<Vec<Field>> {
+        let (format, rest, offset) = self.format_of(line)?;
+        format.fields(rest, offset)
+    }
+
+    /// Time of `line` from its `timestamp` capture, in milliseconds since the epoch.
+    pub fn timestamp(&self, line: &str) -> Option<u64> {
+        let (format, rest, _) = self.format_of(line)?;
+        let value = format.regex.captures(rest)?.name("timestamp")?.as_str();
+        parse_timestamp(value, format.timestamp.as_deref()?)
+    }
+}
+
+#[test]
+fn test_user_formats() {
+    let formats = parse_formats(
+        r#"
+# Android logcat, threadtime
+[logcat]
+regex = ^\d\d-\d\d (?P<timestamp>\d\d:\d\d:\d\d\.\d+)\s+\d+\s+\d+ (?P<level>[VDIWEF]) (?P<module>[^:]+): (?P<msg>.*)
+timestamp = %H:%M:%S%.3f
+
+[python]
+regex = ^(?P<timestamp>\S+ \S+) (?P<level>\w+) (?P<module>[\w.]+): (?P<msg>.*)
+timestamp = %Y-%m-%d %H:%M:%S,%3f
+"#,
+    )
+    .unwrap();
+    assert_eq!(formats.len(), 2);
+
+    let set = FormatSet::new(formats, vec!["[app] ".to_string(), "[py] ".to_string()]);
+    set.detect(1, "not a match");
+    set.detect(1, "2024-05-01 12:00:00,250 WARNING app.db: slow");
+    assert_eq!(set.chosen(1), Some("python"));
+    assert_eq!(set.chosen(0), None);
+
+    let line = "[py] 2024-05-01 12:00:00,250 WARNING app.db: slow";
+    let fields = set.fields(line).unwrap();
+    let level = fields.iter().find(|f| f.key == "level").unwrap();
+    assert_eq!(level.value, "WARNING");
+    assert_eq!(&line[level.range.clone().unwrap()], "WARNING");
+    let expected = parse_timestamp("2024-05-01 12:00:00.250", "%Y-%m-%d %H:%M:%S%.3f");
+    assert_eq!(set.timestamp(line), expected);
+    // Lines of a source without a format have no fields
+    assert!(set
+        .fields("[app] 05-01 12:00:00.000  1  2 W tag: x")
+        .is_none());
+    // Only what is given the set parses with it
+    let set = std::sync::Arc::new(set);
+    assert!(crate::fields::parse_fields(line, Some(&set)).is_some());
+    assert_eq!(crate::fields::parse_fields(line, None), None);
+    let detector = crate::level::LevelDetector::new(Vec::new()).with_formats(Some(set.clone()));
+    assert_eq!(
+        detector.formats().map(|f| f.chosen(1)),
+        Some(Some("python"))
+    );
+
+    assert_eq!(
+        parse_timestamp("1700000000.5", "epoch"),
+        Some(1_700_000_000_500)
+    );
+    // A time without a date is today
+    let noon = parse_timestamp("12:00:00.000", "%H:%M:%S%.3f").unwrap();
+    assert_eq!(
+        parse_timestamp("12:00:00.250", "%H:%M:%S%.3f"),
+        Some(noon + 250)
+    );
+    assert!(noon.abs_diff(crate::pages::now_millis()) < 24 * 3600 * 1000);
+    assert_eq!(
+        parse_timestamp("2024-01-01T00:00:00+00:00", "%Y-%m-%dT%H:%M:%S%:z"),
+        Some(1_704_067_200_000)
+    );
+    assert_eq!(
+        parse_formats("regex = x").unwrap_err(),
+        "line 1: regex outside of a [format]"
+    );
+    assert_eq!(
+        parse_formats("[a]\ntimestamp = %H").unwrap_err(),
+        "line 1: [a] has no regex"
+    );
+}
@@ -0,0 +1,136 @@
+use std::ops::Range;
+
+use ratatui::style::{Color, Modifier, Style};
+
+use crate::command::{MatchOptions, Matcher, SearchPattern};
+
+/// Colors every match of `pattern`, without ever hiding a line.
+///
+/// Written as `PATTERN=COLOR`, e.g. `ERROR=red` or `/req-[0-9a-f]+/=cyan`. The
+/// color is a name, an index of the 256 color palette or `#rrggbb`.
+#[derive(Debug, Clone)]
+pub struct HighlightRule {
+    pub pattern: SearchPattern,
+    pub color: Color,
+}
+
+impl HighlightRule {
+    /// Parses `spec` with `options` applied to the pattern.
+    pub fn parse_with(spec: &str, options: MatchOptions) -> Result<Self, String> {
+        let Some((text, color)) = spec.rsplit_once('=') else {
+            return Err("expected PATTERN=COLOR".to_string());
+        };
+        let color = color
+            .trim()
+            .parse()
+            .map_err(|_| format!("unknown color '{}'", color.trim()))?;
+        let (text, regex) = match text.strip_prefix('/').and_then(|t| t.strip_suffix('/')) {
+            Some(text) => (text, true),
+            None => (text, false),
+        };
+        if text.is_empty() {
+            return Err("empty pattern".to_string());
+        }
+        let pattern = SearchPattern::new(text, regex, options).map_err(|err| err.to_string())?;
+        Ok(Self { pattern, color })
+    }
+
+    fn style(&self) -> Style {
+        Style::default().fg(self.color).add_modifier(Modifier::BOLD)
+    }
+}
+
+impl std::str::FromStr for HighlightRule {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        Self::parse_with(spec, MatchOptions::default())
+    }
+}
+
+/// Written back in the form it is parsed from, with the options inlined.
+impl std::fmt::Display for HighlightRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.pattern {
+            SearchPattern::Regex(regex) => write!(f, "/{}/={}", regex, self.color),
+            SearchPattern::Substring(text) => write!(f, "{}={}", text, self.color),
+        }
+    }
+}
+
+/// Highlight rules that stay on whatever the filter and search are.
+#[derive(Debug, Clone, Default)]
+pub struct HighlightRules {
+    rules: Vec<HighlightRule>,
+}
+
+impl HighlightRules {
+    pub fn rules(&self) -> &[HighlightRule] {
+        &self.rules
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Adds `rule`, replacing the color of an existing rule with the same pattern.
+    pub fn push(&mut self, rule: HighlightRule) {
+        let pattern = rule.pattern.to_string();
+        match self
+            .rules
+            .iter_mut()
+            .find(|r| r.pattern.to_string() == pattern)
+        {
+            Some(existing) => existing.color = rule.color,
+            None => self.rules.push(rule),
+        }
+    }
+
+    /// Removes the most recently added rule.
+    pub fn pop(&mut self) -> Option<HighlightRule> {
+        self.rules.pop()
+    }
+
+    /// Styled ranges of `line`, later rules drawn over earlier ones.
+    pub fn spans(&self, line: &str) -> Vec<(Range<usize>, Style)> {
+        self.rules
+            .iter()
+            .flat_map(|rule| {
+                let style = rule.style();
+                rule.pattern
+                    .match_ranges(line)
+                    .into_iter()
+                    .map(move |range| (range, style))
+            })
+            .collect()
+    }
+}
+
+impl From<Vec<HighlightRule>> for HighlightRules {
+    fn from(rules: Vec<HighlightRule>) -> Self {
+        Self { rules }
+    }
+}
+
+#[test]
+fn test_highlight_rules() {
+    let rule: HighlightRule = "/req-[0-9a-f]+/=cyan".parse().unwrap();
+    assert_eq!(rule.color, Color::Cyan);
+    assert_eq!(rule.to_string(), "/req-[0-9a-f]+/=Cyan");
+    assert_eq!(
+        "a=b".parse::<HighlightRule>().unwrap_err(),
+        "unknown color 'b'"
+    );
+    assert!("ERROR".parse::<HighlightRule>().is_err());
+
+    let mut rules = HighlightRules::default();
+    rules.push("ERROR=red".parse().unwrap());
+    rules.push(rule);
+    rules.push("ERROR=#ff8000".parse().unwrap());
+    assert_eq!(rules.rules().len(), 2);
+    assert_eq!(rules.rules()[0].color, Color::Rgb(255, 128, 0));
+
+    let spans = rules.spans("ERROR req-42 ERROR");
+    let ranges: Vec<_> = spans.iter().map(|(range, _)| range.clone()).collect();
+    assert_eq!(ranges, [0..5, 13..18, 6..12]);
+}
@@ -0,0 +1,187 @@
+use std::{
+    sync::{
+        atomic::Ordering,
+        mpsc::{Receiver, RecvTimeoutError, TryRecvError},
+        Arc,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    level::LevelDetector,
+    pages::PagesWriter,
+    record::{RecordJoiner, RecordStart},
+    source::{SourceEvent, SourceEventKind, SourceId, SourceMessage},
+    sync_child::IngestStats,
+};
+
+/// Longest time appended lines stay invisible to readers while output keeps streaming in.
+const PUBLISH_INTERVAL: Duration = Duration::from_millis(16);
+
+/// How often age based retention is checked while no output arrives.
+const EXPIRE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long a record waits for more continuation lines before it is appended.
+const RECORD_IDLE: Duration = Duration::from_millis(100);
+
+/// Notifications sent from the ingest thread to the UI.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IngestEvent {
+    /// New lines are visible in the shared snapshot
+    Published,
+    /// A source changed state, any message about it is already appended
+    Source(SourceEvent),
+    /// Every source closed, the ingest thread is about to return its writer
+    Closed,
+}
+
+/// What the lines of each source start with once appended, their name when
+/// there is more than one source.
+pub fn source_prefixes(names: &[String]) -> Vec<String> {
+    if names.len() > 1 {
+        names.iter().map(|name| format!("[{}] ", name)).collect()
+    } else {
+        vec![String::new(); names.len()]
+    }
+}
+
+/// Appends batches from `rx` on a dedicated thread until every sender is gone.
+///
+/// Snapshots are published when the queue runs dry or every `PUBLISH_INTERVAL`,
+/// so a flood of output costs at most one copy of the newest page per interval.
+/// The writer is handed back through the join handle so the caller can append
+/// after the sources have closed.
+///
+/// `names` are indexed by source id, lines are prefixed as in [`source_prefixes`].
+/// With `records`, lines are joined into multi-line records before they are
+/// appended. With `levels`, appended lines are counted per level in `stats`, and
+/// each source's format is picked from its lines among the detector's formats,
+/// see [`crate::format::FormatSet`].
+pub fn spawn_ingest(
+    rx: Receiver<SourceMessage>,
+    mut writer: PagesWriter,
+    stats: Arc<IngestStats>,
+    names: Vec<String>,
+    records: Option<RecordStart>,
+    levels: Option<Arc<LevelDetector>>,
+    notify: impl Fn(IngestEvent) + Send + 'static,
+) -> JoinHandle<PagesWriter> {
+    std::thread::spawn(move || {
+        let mut started = vec![Instant::now(); names.len()];
+        let mut joiner = records.map(RecordJoiner::new);
+        let prefixes = source_prefixes(&names);
+        let add = |writer: &mut PagesWriter, source: SourceId, line: &str| {
+            if let Some(formats) = levels.as_ref().and_then(|d| d.formats()) {
+                formats.detect(source, line);
+            }
+            let prefix = &prefixes[source];
+            let line = if prefix.is_empty() {
+                std::borrow::Cow::Borrowed(line)
+            } else {
+                std::borrow::Cow::Owned(format!("{}{}", prefix, line))
+            };
+            if let Some(level) = levels.as_ref().and_then(|d| d.detect(&line)) {
+                stats.levels[level.index()].fetch_add(1, Ordering::Relaxed);
+            }
+            writer.add_line(&line);
+        };
+        let mut append = |writer: &mut PagesWriter,
+                          joiner: &mut Option<RecordJoiner>,
+                          message: SourceMessage| match message {
+            SourceMessage::Lines { source, lines } => {
+                for line in &lines {
+                    match joiner {
+                        Some(joiner) => {
+                            if let Some(record) = joiner.push(source, line) {
+                                add(writer, source, &record);
+                            }
+                        }
+                        None => add(writer, source, line),
+                    }
+                }
+                stats.queued.fetch_sub(lines.len(), Ordering::Relaxed);
+                stats.appended.fetch_add(lines.len(), Ordering::Relaxed);
+            }
+            SourceMessage::Event(event) => {
+                // A source's last record is complete once it stops
+                if let Some(record) = joiner.as_mut().and_then(|j| j.flush(event.source)) {
+                    add(writer, event.source, &record);
+                }
+                let name = &names[event.source];
+                match &event.kind {
+                    SourceEventKind::Started => started[event.source] = Instant::now(),
+                    SourceEventKind::Exited(Some(status)) => writer.add_line(&format!(
+                        "{} exited with {} and time took {:?}",
+                        name,
+                        status,
+                        started[event.source].elapsed()
+                    )),
+                    SourceEventKind::Exited(None) => writer.add_line(&format!(
+                        "{} closed after {:?}",
+                        name,
+                        started[event.source].elapsed()
+                    )),
+                    SourceEventKind::Error(err) => {
+                        writer.add_line(&format!("{} failed: {}", name, err))
+                    }
+                }
+                notify(IngestEvent::Source(event));
+            }
+        };
+        // Appends the records that stopped growing, returns true if there were any
+        let flush_idle = |writer: &mut PagesWriter, joiner: &mut Option<RecordJoiner>| {
+            let records = joiner
+                .as_mut()
+                .map(|j| j.flush_idle(RECORD_IDLE))
+                .unwrap_or_default();
+            for (source, record) in &records {
+                add(writer, *source, record);
+            }
+            !records.is_empty()
+        };
+
+        loop {
+            let pending = joiner.as_ref().is_some_and(|j| j.has_pending());
+            let timeout = if pending {
+                RECORD_IDLE
+            } else {
+                EXPIRE_INTERVAL
+            };
+            let batch = match rx.recv_timeout(timeout) {
+                Ok(batch) => batch,
+                Err(RecvTimeoutError::Timeout) => {
+                    let flushed = flush_idle(&mut writer, &mut joiner);
+                    if writer.expire() || flushed {
+                        writer.publish();
+                        notify(IngestEvent::Published);
+                    }
+                    continue;
+                }
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+            append(&mut writer, &mut joiner, batch);
+
+            loop {
+                if writer.since_publish() >= PUBLISH_INTERVAL {
+                    break;
+                }
+                match rx.try_recv() {
+                    Ok(batch) => append(&mut writer, &mut joiner, batch),
+                    Err(TryRecvError::Empty) | Err(TryRecvError::Disconnected) => break,
+                }
+            }
+            flush_idle(&mut writer, &mut joiner);
+            writer.publish();
+            notify(IngestEvent::Published);
+        }
+
+        log::info!("all line sources closed, stopping ingest");
+        for (source, record) in joiner.as_mut().map(|j| j.flush_all()).unwrap_or_default() {
+            add(&mut writer, source, &record);
+        }
+        writer.publish();
+        notify(IngestEvent::Closed);
+        writer
+    })
+}
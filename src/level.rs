@@ -0,0 +1,202 @@
+//! Log level detection.
+//!
+//! Levels are recognised in the common shapes they are printed in:
+//!
+//! ```text
+//! level=warn  "level":"error"  severity: INFO
+//! [error] [WARN]
+//! W/flutter (1234): ...        Android logcat
+//! 2024-01-01 12:00:00 INFO ...
+//! ```
+
+use std::{ops::Range, sync::Arc};
+
+use ratatui::style::Color;
+use regex::Regex;
+
+use crate::{command::Matcher, format::FormatSet};
+
+/// Severity of a line, ordered from least to most severe.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Level {
+    Trace,
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl Level {
+    pub const ALL: [Level; 5] = [
+        Level::Trace,
+        Level::Debug,
+        Level::Info,
+        Level::Warn,
+        Level::Error,
+    ];
+
+    /// Parses the usual spellings of a level, in any case, including the
+    /// single letters of logcat.
+    pub fn from_name(name: &str) -> Option<Self> {
+        let level = match name.to_ascii_lowercase().as_str() {
+            "trace" | "trc" | "verbose" | "v" => Level::Trace,
+            "debug" | "dbg" | "d" => Level::Debug,
+            "info" | "inf" | "information" | "notice" | "i" => Level::Info,
+            "warn" | "warning" | "wrn" | "w" => Level::Warn,
+            "error" | "err" | "fatal" | "critical" | "crit" | "panic" | "severe" | "alert"
+            | "emerg" | "e" | "f" => Level::Error,
+            _ => return None,
+        };
+        Some(level)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Level::Trace => "trace",
+            Level::Debug => "debug",
+            Level::Info => "info",
+            Level::Warn => "warn",
+            Level::Error => "error",
+        }
+    }
+
+    /// Color lines of this level are drawn in, `None` for the default.
+    pub fn color(&self) -> Option<Color> {
+        match self {
+            Level::Trace => Some(Color::DarkGray),
+            Level::Debug => Some(Color::Cyan),
+            Level::Info => None,
+            Level::Warn => Some(Color::Yellow),
+            Level::Error => Some(Color::Red),
+        }
+    }
+
+    /// Position in [`Level::ALL`].
+    pub fn index(&self) -> usize {
+        *self as usize
+    }
+}
+
+impl std::fmt::Display for Level {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// Patterns tried after the user's, see the module docs.
+const BUILTIN_PATTERNS: &[&str] = &[
+    r#"(?i)\b"?(?:level|lvl|severity|loglevel)"?\s*[=:]\s*"?(?P<level>[a-z]+)"#,
+    r"\[(?P<level>[A-Za-z]+)\]",
+    // logcat's brief and time formats, after an optional source prefix
+    r"^(?:\[[^\]]+\] )?(?:[\d-]+ [\d:.]+\s+)?(?P<level>[VDIWEF])/[^\s(:]+\s*[(:]",
+    r"\b(?P<level>TRACE|DEBUG|INFO|NOTICE|WARN|WARNING|ERROR|ERR|FATAL|CRITICAL|PANIC)\b",
+];
+
+/// Finds the level of a line with a list of regexes.
+///
+/// The level is taken from the `level` capture group, or the whole match if a
+/// pattern has none. The first match naming a known level wins.
+#[derive(Debug)]
+pub struct LevelDetector {
+    patterns: Vec<Regex>,
+    formats: Option<Arc<FormatSet>>,
+}
+
+impl LevelDetector {
+    /// Tries `patterns` before the built-in ones.
+    pub fn new(patterns: Vec<Regex>) -> Self {
+        let builtin = BUILTIN_PATTERNS
+            .iter()
+            .map(|pattern| Regex::new(pattern).expect("valid built-in level pattern"));
+        Self {
+            patterns: patterns.into_iter().chain(builtin).collect(),
+            formats: None,
+        }
+    }
+
+    /// Reads the `level` field of lines in one of `formats`, see [`crate::format`].
+    pub fn with_formats(mut self, formats: Option<Arc<FormatSet>>) -> Self {
+        self.formats = formats;
+        self
+    }
+
+    pub fn formats(&self) -> Option<&Arc<FormatSet>> {
+        self.formats.as_ref()
+    }
+
+    /// The `level` field of a user-defined format comes before any pattern.
+    pub fn detect(&self, line: &str) -> Option<Level> {
+        let field = self
+            .formats
+            .as_ref()
+            .and_then(|formats| formats.fields(line))
+            .and_then(|fields| fields.into_iter().find(|field| field.key == "level"));
+        if let Some(level) = field.and_then(|field| Level::from_name(&field.value)) {
+            return Some(level);
+        }
+        self.patterns.iter().find_map(|pattern| {
+            pattern.captures_iter(line).find_map(|captures| {
+                let name = captures.name("level").or_else(|| captures.get(0))?;
+                Level::from_name(name.as_str())
+            })
+        })
+    }
+}
+
+impl Default for LevelDetector {
+    fn default() -> Self {
+        Self::new(Vec::new())
+    }
+}
+
+/// Keeps the lines at or above a level, hiding those without one.
+#[derive(Debug, Clone)]
+pub struct LevelFilter {
+    pub min: Level,
+    detector: Arc<LevelDetector>,
+}
+
+impl LevelFilter {
+    pub fn new(min: Level, detector: Arc<LevelDetector>) -> Self {
+        Self { min, detector }
+    }
+}
+
+impl Matcher for LevelFilter {
+    fn is_match(&self, s: &str) -> Option<Range<usize>> {
+        let level = self.detector.detect(s)?;
+        (level >= self.min).then_some(0..0)
+    }
+}
+
+#[test]
+fn test_level_detection() {
+    let detector = LevelDetector::default();
+    let level = |line| detector.detect(line);
+    assert_eq!(level("ts=1 level=warn msg=slow"), Some(Level::Warn));
+    assert_eq!(
+        level(r#"{"level":"error","msg":"boom"}"#),
+        Some(Level::Error)
+    );
+    assert_eq!(level("[app] [error] disk full"), Some(Level::Error));
+    assert_eq!(level("W/flutter (1234): jank"), Some(Level::Warn));
+    assert_eq!(
+        level("05-01 12:00:00.000 E/ActivityManager( 512): ANR"),
+        Some(Level::Error)
+    );
+    assert_eq!(level("disk I/O ERROR on sda"), Some(Level::Error));
+    assert_eq!(level("2024-01-01 12:00:00 INFO started"), Some(Level::Info));
+    assert_eq!(level("I think the info is fine"), None);
+
+    let custom = LevelDetector::new(vec![Regex::new(r"^<(?P<level>\w)>").unwrap()]);
+    assert_eq!(
+        custom.detect("<d> ERROR in a debug line"),
+        Some(Level::Debug)
+    );
+    assert_eq!(custom.detect("plain ERROR"), Some(Level::Error));
+
+    let filter = LevelFilter::new(Level::Warn, Arc::new(detector));
+    assert!(filter.is_match("level=error").is_some());
+    assert!(filter.is_match("level=info").is_none());
+    assert!(filter.is_match("no level").is_none());
+}
@@ -0,0 +1,45 @@
+//! Building blocks of the `filter` TUI, usable on their own in other ratatui apps.
+//!
+//! - [`pages::Pages`] is a ring buffer of lines split into fixed capacity pages,
+//!   with retention limits, folding of duplicates and cheap snapshots.
+//! - [`pages::PagesWriter`] and [`pages::SharedPages`] split it into a write side
+//!   for an ingest thread and a read side for the UI, see [`ingest::spawn_ingest`].
+//! - [`source::LineSource`] is anything lines come from: a child process,
+//!   stdin, a followed file, a TCP socket or a replayed recording.
+//! - [`session::Session`] persists lines to disk so they can be reloaded later.
+//! - [`command::Matcher`] is what filters and searches run against each line.
+//! - [`highlight::HighlightRules`] color patterns without hiding any line.
+//! - [`format::FormatSet`] parses user-defined formats into fields.
+//! - [`level::LevelDetector`] finds the log level of a line for coloring and
+//!   [`level::LevelFilter`].
+//! - [`timerange::TimeRange`] keeps the lines within a time range.
+//! - [`new_scroll::PageScrollState`] and [`new_scroll::PageScroll`] scroll, filter
+//!   and highlight the buffer as a ratatui `StatefulWidget`.
+//!
+//! ```
+//! use filter_rs::command::{Matcher, SearchPattern};
+//! use filter_rs::pages::Pages;
+//!
+//! let mut pages = Pages::new(64 * 1024, 16);
+//! pages.extend(["starting", "ERROR boom", "done"]);
+//!
+//! let errors = SearchPattern::Substring("ERROR".into());
+//! assert_eq!(pages.find_all_matches(&errors), vec![1]);
+//! assert_eq!(errors.is_match("ERROR boom"), Some(0..5));
+//! ```
+
+pub mod command;
+pub mod fields;
+pub mod filter;
+pub mod format;
+pub mod highlight;
+pub mod ingest;
+pub mod level;
+pub mod new_scroll;
+pub mod pages;
+pub mod query;
+pub mod record;
+pub mod session;
+pub mod source;
+pub mod sync_child;
+pub mod timerange;
@@ -1,22 +1,67 @@
 mod action;
 mod app;
-mod command;
 mod main_pane;
-mod new_scroll;
-mod pages;
-mod sync_child;
 
 use app::App;
 use clap::Parser;
+use filter_rs::fields::Columns;
+use filter_rs::format::{self, FormatSet};
+use filter_rs::highlight::HighlightRule;
+use filter_rs::level::LevelDetector;
+use filter_rs::pages::{Collapse, Pages, Retention};
+use filter_rs::record::RecordStart;
+use filter_rs::session::{Session, SessionState};
+use filter_rs::source::{FileSource, LineSource, ReplaySource, SocketSource, StdinSource};
+use filter_rs::sync_child::{ChildSource, OverflowPolicy};
 use std::io::Write;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
     /// Command and arguments to run
-    #[arg(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+    #[arg(
+        required_unless_present_any = ["stdin", "file", "listen", "replay", "resume"],
+        trailing_var_arg = true,
+        allow_hyphen_values = true
+    )]
     child_args: Vec<String>,
 
+    /// Read lines piped into stdin
+    #[arg(long)]
+    stdin: bool,
+
+    /// Follow a file as it grows, like `tail -f` (repeatable)
+    #[arg(long, value_name = "PATH")]
+    file: Vec<std::path::PathBuf>,
+
+    /// Accept lines over TCP on this address, e.g. 127.0.0.1:7000 (repeatable)
+    #[arg(long, value_name = "ADDR")]
+    listen: Vec<String>,
+
+    /// Play back a recorded file once (repeatable)
+    #[arg(long, value_name = "PATH")]
+    replay: Vec<std::path::PathBuf>,
+
+    /// Lines per second for --replay, as fast as possible if unset
+    #[arg(long)]
+    replay_rate: Option<u32>,
+
+    /// Persist lines, filter and search to this directory as they change
+    #[arg(long, value_name = "DIR", conflicts_with = "resume")]
+    session: Option<std::path::PathBuf>,
+
+    /// Reopen a session directory and keep appending to it
+    #[arg(long, value_name = "DIR")]
+    resume: Option<std::path::PathBuf>,
+
+    /// With --resume and no command given, run the session's command again
+    #[arg(long, requires = "resume")]
+    restart: bool,
+
+    /// Disk space a session may use before its oldest lines are deleted (e.g., 64MB, 1GB)
+    #[arg(long, value_parser = parse_size, default_value = "256MB")]
+    session_size: usize,
+
     /// Maximum buffer size (e.g., 10MB, 1GB). If set, it will be divided into 64KB pages.
     #[arg(long, value_parser = parse_size)]
     max_buffer_size: Option<usize>,
@@ -28,6 +73,77 @@ struct Args {
     /// Size of each page in the circular buffer (e.g., 64KB, 1MB)
     #[arg(long, value_parser = parse_size, default_value = "64KB")]
     page_size: usize,
+
+    /// Truncate single lines longer than this for good (e.g., 256KB), at least 1KB.
+    /// By default a line may take the whole buffer
+    #[arg(long, value_parser = parse_line_size)]
+    max_line_size: Option<usize>,
+
+    /// Keep at most this many lines, on top of the byte budget
+    #[arg(long)]
+    max_lines: Option<usize>,
+
+    /// Drop lines older than this (e.g., 90s, 30m, 12h, 7d)
+    #[arg(long, value_parser = parse_duration)]
+    max_age: Option<std::time::Duration>,
+
+    /// Regex of lines to keep in a side store when they are evicted (e.g., 'ERROR|panicked')
+    #[arg(long)]
+    retain: Option<regex::Regex>,
+
+    /// Size of the side store for --retain (e.g., 512KB, 4MB)
+    #[arg(long, value_parser = parse_size, default_value = "1MB")]
+    retain_size: usize,
+
+    /// Join indented lines and stack traces into the record above them
+    #[arg(long)]
+    records: bool,
+
+    /// Start a new record at each line matching this regex, joining the others to it
+    #[arg(long, value_name = "REGEX")]
+    record_start: Option<regex::Regex>,
+
+    /// Lines to show after each filter match
+    #[arg(short = 'A', long, value_name = "N")]
+    after_context: Option<usize>,
+
+    /// Lines to show before each filter match
+    #[arg(short = 'B', long, value_name = "N")]
+    before_context: Option<usize>,
+
+    /// Lines to show before and after each filter match
+    #[arg(short = 'C', long, value_name = "N")]
+    context: Option<usize>,
+
+    /// Always color matches of a pattern, e.g. 'ERROR=red' or '/req-[0-9a-f]+/=cyan' (repeatable)
+    #[arg(long, value_name = "PATTERN=COLOR")]
+    highlight: Vec<HighlightRule>,
+
+    /// Regex finding the level of a line, tried before the built-in ones. The level
+    /// is its `level` capture group or the whole match (repeatable)
+    #[arg(long, value_name = "REGEX")]
+    level_pattern: Vec<regex::Regex>,
+
+    /// File of log formats to parse lines with, defaults to $FILTER_FORMATS or
+    /// ~/.config/filter/formats if it exists
+    #[arg(long, value_name = "PATH")]
+    formats: Option<std::path::PathBuf>,
+
+    /// Show JSON lines as these fields, e.g. 'time,level:5,msg', instead of the whole object
+    #[arg(long, value_name = "FIELD[:WIDTH],...")]
+    columns: Option<Columns>,
+
+    /// Fold consecutive duplicate lines into one entry with a repeat count
+    #[arg(long, value_enum, default_value_t = Collapse::Off)]
+    collapse: Collapse,
+
+    /// What to do with new output when the UI can't keep up
+    #[arg(long, value_enum, default_value_t = OverflowPolicy::Block)]
+    overflow: OverflowPolicy,
+
+    /// Upper bound on redraws per second while output is streaming
+    #[arg(long, default_value_t = 30)]
+    max_fps: u32,
 }
 
 fn parse_size(s: &str) -> Result<usize, String> {
@@ -54,6 +170,35 @@ fn parse_size(s: &str) -> Result<usize, String> {
     }
 }
 
+/// Smallest `--max-line-size`, so a truncated line keeps some text next to its marker.
+const MIN_LINE_SIZE: usize = 1024;
+
+fn parse_line_size(s: &str) -> Result<usize, String> {
+    let size = parse_size(s)?;
+    if size < MIN_LINE_SIZE {
+        return Err(format!("must be at least {}KB", MIN_LINE_SIZE / 1024));
+    }
+    Ok(size)
+}
+
+fn parse_duration(s: &str) -> Result<std::time::Duration, String> {
+    let s = s.trim().to_lowercase();
+    let (num, unit_secs) = if let Some(n) = s.strip_suffix('d') {
+        (n, 24 * 60 * 60)
+    } else if let Some(n) = s.strip_suffix('h') {
+        (n, 60 * 60)
+    } else if let Some(n) = s.strip_suffix('m') {
+        (n, 60)
+    } else if let Some(n) = s.strip_suffix('s') {
+        (n, 1)
+    } else {
+        (s.as_str(), 1)
+    };
+    num.parse::<u64>()
+        .map(|n| std::time::Duration::from_secs(n * unit_secs))
+        .map_err(|e| e.to_string())
+}
+
 // #[tokio::main]
 fn main() -> anyhow::Result<()> {
     let args = Args::parse();
@@ -65,15 +210,101 @@ fn main() -> anyhow::Result<()> {
 fn start_ratatui(args: Args) -> anyhow::Result<()> {
     let (pages_count, page_size) = if let Some(max_buffer_size) = args.max_buffer_size {
         let page_size = 64 * 1024;
-        let pages_count = (max_buffer_size + page_size - 1) / page_size;
+        let pages_count = max_buffer_size.div_ceil(page_size);
         (pages_count, page_size)
     } else {
         (args.pages_count, args.page_size)
     };
 
+    let retention = Retention {
+        max_lines: args.max_lines,
+        max_age: args.max_age,
+        retain: args.retain,
+        retain_size: args.retain_size,
+    };
+    let mut pages = Pages::new(page_size, pages_count)
+        .with_retention(retention)
+        .with_collapse(args.collapse);
+    if let Some(max_line_size) = args.max_line_size {
+        pages = pages.with_max_line_size(max_line_size);
+    }
+
+    let mut child_args = args.child_args;
+    let mut session = None;
+    let mut session_state = None;
+    if let Some(dir) = &args.resume {
+        session = Some(Session::resume(dir, args.session_size as u64, &mut pages)?);
+        let mut state = SessionState::load(dir)?;
+        if child_args.is_empty() && args.restart {
+            child_args = state.command.clone();
+        } else if !child_args.is_empty() {
+            state.command = child_args.clone();
+        }
+        session_state = Some(state);
+    } else if let Some(dir) = &args.session {
+        session = Some(Session::create(dir, args.session_size as u64)?);
+        session_state = Some(SessionState {
+            command: child_args.clone(),
+            ..Default::default()
+        });
+    }
+
+    let mut sources: Vec<Box<dyn LineSource>> = Vec::new();
+    if !child_args.is_empty() {
+        sources.push(Box::new(ChildSource { args: child_args }));
+    }
+    if args.stdin {
+        sources.push(Box::new(StdinSource));
+    }
+    for path in args.file {
+        sources.push(Box::new(FileSource { path }));
+    }
+    for addr in args.listen {
+        sources.push(Box::new(SocketSource { addr }));
+    }
+    for path in args.replay {
+        sources.push(Box::new(ReplaySource {
+            path,
+            rate: args.replay_rate,
+        }));
+    }
+
+    let formats = match formats_path(args.formats) {
+        Some(path) => {
+            let names: Vec<String> = sources.iter().map(|s| s.name()).collect();
+            let prefixes = filter_rs::ingest::source_prefixes(&names);
+            let formats = FormatSet::new(format::load_formats(&path)?, prefixes);
+            Some(std::sync::Arc::new(formats))
+        }
+        None => None,
+    };
+
+    // Sources are started before taking over the terminal, so errors print normally
+    let records = match args.record_start {
+        Some(regex) => Some(RecordStart::Regex(regex)),
+        None if args.records => Some(RecordStart::Heuristic),
+        None => None,
+    };
+    let mut app = App::new(
+        sources,
+        pages,
+        session,
+        records,
+        std::sync::Arc::new(LevelDetector::new(args.level_pattern).with_formats(formats)),
+        args.overflow,
+        args.max_fps,
+    )?;
+    if let Some(state) = session_state {
+        app.set_session_state(state);
+    }
+    app.add_highlight_rules(args.highlight);
+    app.scroll_state.set_columns(args.columns);
+    app.scroll_state.set_context(
+        args.before_context.or(args.context).unwrap_or(0),
+        args.after_context.or(args.context).unwrap_or(0),
+    );
     crossterm::execute!(std::io::stdout(), crossterm::event::EnableMouseCapture)?;
     let mut term = ratatui::init();
-    let mut app = App::new(args.child_args, pages_count, page_size)?;
     let result = app.run(&mut term);
 
     ratatui::restore();
@@ -85,6 +316,20 @@ fn start_ratatui(args: Args) -> anyhow::Result<()> {
     Ok(())
 }
 
+/// The formats file given, or the default one if it exists.
+fn formats_path(path: Option<std::path::PathBuf>) -> Option<std::path::PathBuf> {
+    if let Some(path) = path.or_else(|| std::env::var_os("FILTER_FORMATS").map(Into::into)) {
+        return Some(path);
+    }
+    let config = std::env::var_os("XDG_CONFIG_HOME")
+        .map(std::path::PathBuf::from)
+        .or_else(|| {
+            std::env::var_os("HOME").map(|home| std::path::Path::new(&home).join(".config"))
+        })?;
+    let path = config.join("filter").join("formats");
+    path.exists().then_some(path)
+}
+
 fn init_logger() {
     use log::LevelFilter;
     use std::fs::File;
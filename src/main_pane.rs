@@ -3,26 +3,30 @@ use ratatui::{
         Constraint::{Length, Min},
         Layout,
     },
-    style::Style,
+    style::{Color, Modifier, Style},
+    text::Line,
     widgets::{Block, Clear, Paragraph},
     Frame,
 };
 
-use crate::{command::FilterTitleWidget, new_scroll::PageScrollWidget};
+use filter_rs::{command::FilterTitleWidget, level::Level, new_scroll::PageScroll};
 
 pub fn main_pane_with_page_scroll_draw(frame: &mut Frame, app: &mut crate::app::App) {
     let vertical = Layout::vertical([Length(3), Min(0), Length(1)]);
     let [title_area, main_area, status_area] = vertical.areas(frame.area());
 
-    let active_filter = app.scroll_state.filter().map(|f| f.to_string());
+    let active_filter = app.scroll_state.filters().summary();
     let active_search = app.search_query.as_ref().map(|s| s.to_string());
+    let title = if app.scroll_state.show_retained() {
+        format!("{} (retained)", app.title)
+    } else {
+        app.title.clone()
+    };
     frame.render_widget(
-        FilterTitleWidget::new(&app.cmd_builder, active_filter, active_search, &app.title),
+        FilterTitleWidget::new(&app.cmd_builder, active_filter, active_search, &title),
         title_area,
     );
-    app.scroll_state
-        .set_size(main_area.width as usize, main_area.height as usize);
-    frame.render_widget(PageScrollWidget(&app.scroll_state), main_area);
+    frame.render_stateful_widget(PageScroll, main_area, &mut app.scroll_state);
 
     if app.error_timer.error.is_empty() {
         let scroll_status = if app.scroll_state.auto_scroll() {
@@ -35,14 +39,24 @@ pub fn main_pane_with_page_scroll_draw(frame: &mut Frame, app: &mut crate::app::
         } else {
             "Numbers: OFF"
         };
-        let match_status = if let Some((rank, total)) = app.scroll_state.get_match_status() {
+        let match_status = if let Some(preview) = &app.preview {
+            match preview.count {
+                Some(count) => format!(" | Preview: {} lines", count),
+                None => " | Preview: counting…".to_string(),
+            }
+        } else if let Some((rank, total)) = app.scroll_state.get_match_status() {
             format!(" | Matches: {}/{}", rank, total)
         } else {
             "".to_string()
         };
         let status = format!(
-            "{} | {}{} | <space> menu",
-            scroll_status, line_numbers_status, match_status
+            "{} | {}{} | {}{}{} | <space> menu",
+            scroll_status,
+            line_numbers_status,
+            match_status,
+            ingest_status(app),
+            level_status(app),
+            retention_status(app)
         );
         frame.render_widget(Block::bordered().title(status), status_area);
     } else {
@@ -56,12 +70,101 @@ pub fn main_pane_with_page_scroll_draw(frame: &mut Frame, app: &mut crate::app::
     }
 }
 
+fn ingest_status(app: &crate::app::App) -> String {
+    use std::sync::atomic::Ordering;
+
+    let mut status = format!("{} lines/s", app.line_rate.per_sec());
+    let queued = app.ingest_stats.queued.load(Ordering::Relaxed);
+    if queued > 0 {
+        status.push_str(&format!(", queued {}", queued));
+    }
+    let dropped = app.ingest_stats.dropped.load(Ordering::Relaxed);
+    if dropped > 0 {
+        status.push_str(&format!(", dropped {}", dropped));
+    }
+    status
+}
+
+/// Lines seen per level, most severe first, leaving out levels never seen.
+fn level_status(app: &crate::app::App) -> String {
+    use std::sync::atomic::Ordering;
+
+    let counts: Vec<String> = Level::ALL
+        .iter()
+        .rev()
+        .filter_map(|level| {
+            let count = app.ingest_stats.levels[level.index()].load(Ordering::Relaxed);
+            (count > 0).then(|| format!("{} {}", level, count))
+        })
+        .collect();
+    if counts.is_empty() {
+        return String::new();
+    }
+    format!(" | {}", counts.join(", "))
+}
+
+/// Oldest retained line, only shown once something has been evicted.
+fn retention_status(app: &crate::app::App) -> String {
+    let pages = app.pages.snapshot();
+    if pages.first_index() == 0 {
+        return String::new();
+    }
+    let time = pages
+        .first_time()
+        .map(|ms| format!(" {}", filter_rs::pages::format_millis(ms)))
+        .unwrap_or_default();
+    let number = pages.line_number(pages.first_index());
+    format!(" | oldest [{}]{}", number, time)
+}
+
 pub fn draw_space_menu(frame: &mut Frame) {
-    const MENU_CONTENT: &'static str =
-        "s search\nr regex\ni ignore\nf filter\nn numbers\na autoscroll\nc clear\n: jump to\n";
-    let horizontal = Layout::horizontal([Min(0), Length(20)]).margin(8);
+    const MENU_CONTENT: &str =
+        "s search\nr regex\ni ignore\nf filter\nl filter layers\nh highlight\nH drop highlight\nC context lines\nv columns\nt time range\n1-5 min level\nn numbers\na autoscroll\nR retained\ne expand folds\nz collapse records\nc clear\n: jump to\n";
+    let horizontal = Layout::horizontal([Min(0), Length(20)])
+        .horizontal_margin(8)
+        .vertical_margin(4);
     let [_, menu_area] = horizontal.areas(frame.area());
+    let height = (MENU_CONTENT.lines().count() as u16 + 2).min(menu_area.height);
+    let [menu_area, _] = Layout::vertical([Length(height), Min(0)]).areas(menu_area);
     frame.render_widget(Clear, menu_area);
     let paragraph = Paragraph::new(MENU_CONTENT).block(Block::bordered().title("Menu"));
     frame.render_widget(paragraph, menu_area);
 }
+
+pub fn draw_filter_panel(frame: &mut Frame, app: &crate::app::App, selected: usize) {
+    const HELP: &str = "space toggle  d delete  J/K move  esc close";
+    let horizontal = Layout::horizontal([Min(0), Length(48)]).margin(8);
+    let [_, panel_area] = horizontal.areas(frame.area());
+
+    let layers = app.scroll_state.filters().layers();
+    let mut lines: Vec<Line> = layers
+        .iter()
+        .enumerate()
+        .map(|(i, layer)| {
+            let check = if layer.enabled { "[x]" } else { "[ ]" };
+            let kind = if layer.is_exclude() { "hide" } else { "only" };
+            let mut style = Style::new();
+            if !layer.enabled {
+                style = style.fg(Color::DarkGray);
+            }
+            if i == selected {
+                style = style.add_modifier(Modifier::REVERSED);
+            }
+            Line::styled(format!("{} {} {}", check, kind, layer.text()), style)
+        })
+        .collect();
+    if lines.is_empty() {
+        lines.push(Line::styled(
+            "No layers, add one with f or i",
+            Style::new().fg(Color::DarkGray),
+        ));
+    }
+    lines.push(Line::default());
+    lines.push(Line::styled(HELP, Style::new().fg(Color::DarkGray)));
+
+    let height = (lines.len() as u16 + 2).min(panel_area.height);
+    let [panel_area, _] = Layout::vertical([Length(height), Min(0)]).areas(panel_area);
+    frame.render_widget(Clear, panel_area);
+    let paragraph = Paragraph::new(lines).block(Block::bordered().title("Filter layers"));
+    frame.render_widget(paragraph, panel_area);
+}
@@ -1,23 +1,136 @@
-use ratatui::{buffer::Buffer, style::Style, widgets::Widget};
+use ratatui::{
+    buffer::Buffer,
+    layout::Rect,
+    style::Style,
+    widgets::{StatefulWidget, Widget},
+};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::ops::Range;
 
 use crate::command::Matcher;
-use crate::pages::Pages;
-use std::sync::{Arc, RwLock};
-
-pub fn get_wrapped_lines(s: &str, width: usize) -> Vec<(Box<str>, Range<usize>)> {
+use crate::fields::{parse_logfmt, Columns};
+use crate::filter::FilterStack;
+use crate::format::FormatSet;
+use crate::highlight::HighlightRules;
+use crate::level::{Level, LevelDetector};
+use crate::pages::{format_millis, Pages, SharedPages};
+use std::sync::Arc;
+
+/// Byte ranges of `s` for each segment it wraps into at `width` columns.
+///
+/// Each physical line of a multi-line record wraps on its own, an empty one
+/// still takes a segment.
+pub fn get_wrapped_ranges(s: &str, width: usize) -> Arc<[Range<usize>]> {
     let options = textwrap::Options::new(width);
-    textwrap::wrap(s, &options)
-        .iter()
-        .map(|x| {
-            let start = x.as_ptr() as usize - s.as_ptr() as usize;
-            (x.as_ref().into(), start..start + x.len())
-        })
-        .collect()
+    let mut ranges = Vec::new();
+    let mut offset = 0;
+    for part in s.split('\n') {
+        if part.is_empty() {
+            ranges.push(offset..offset);
+        } else {
+            ranges.extend(textwrap::wrap(part, &options).iter().map(|x| {
+                let start = offset + (x.as_ptr() as usize - part.as_ptr() as usize);
+                start..start + x.len()
+            }));
+        }
+        offset += part.len() + 1;
+    }
+    ranges.into()
+}
+
+/// Wrapped segment ranges of a line, and where its first physical line ends.
+#[derive(Clone)]
+struct Wrapped {
+    ranges: Arc<[Range<usize>]>,
+    first_break: Option<usize>,
+}
+
+/// Wrapped segment ranges per line index, valid for a single render width.
+///
+/// Lines are immutable once added to `Pages`, so an entry only goes stale when the
+/// render width changes (resize, line numbers toggled) or the line is evicted.
+#[derive(Default)]
+struct WrapCache {
+    width: usize,
+    lines: HashMap<usize, Wrapped>,
+}
+
+impl WrapCache {
+    /// Upper bound on cached lines, so a long scroll session can't grow it forever.
+    const MAX_ENTRIES: usize = 1 << 16;
+
+    /// `text` gives what is drawn for the line, only called on a miss.
+    fn get<'l>(
+        &mut self,
+        idx: usize,
+        width: usize,
+        text: impl FnOnce() -> Cow<'l, str>,
+    ) -> Wrapped {
+        if self.width != width {
+            self.lines.clear();
+            self.width = width;
+        }
+        if let Some(wrapped) = self.lines.get(&idx) {
+            return wrapped.clone();
+        }
+        if self.lines.len() >= Self::MAX_ENTRIES {
+            self.lines.clear();
+        }
+        let text = text();
+        let wrapped = Wrapped {
+            ranges: get_wrapped_ranges(&text, width),
+            first_break: text.find('\n'),
+        };
+        self.lines.insert(idx, wrapped.clone());
+        wrapped
+    }
+
+    fn clear(&mut self) {
+        self.lines.clear();
+    }
+
+    fn remove_before(&mut self, idx: usize) {
+        self.lines.retain(|&i, _| i >= idx);
+    }
+}
+
+/// Whether the filter matched each line index, valid until the filter or the
+/// view changes.
+#[derive(Default)]
+struct MatchCache {
+    lines: HashMap<usize, bool>,
+}
+
+impl MatchCache {
+    const MAX_ENTRIES: usize = 1 << 16;
+
+    fn get(&mut self, pages: &Pages, idx: usize, line: &str, filter: &FilterStack) -> bool {
+        if let Some(&matched) = self.lines.get(&idx) {
+            return matched;
+        }
+        if self.lines.len() >= Self::MAX_ENTRIES {
+            self.lines.clear();
+        }
+        let matched = filter.is_match_at(line, pages.get_time(idx)).is_some();
+        self.lines.insert(idx, matched);
+        matched
+    }
 }
 
+/// How a line shows up under the filter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LineKind {
+    Match,
+    /// Shown, dimmed, because it is near a match
+    Context,
+}
+
+/// Scroll position, filter, search and display toggles for a view over
+/// [`SharedPages`]. Render it with [`PageScroll`].
 pub struct PageScrollState {
-    pages: Arc<RwLock<Pages>>,
+    pages: SharedPages,
     show_line_numbers: bool,
     auto_scroll: bool,
     width: usize,
@@ -31,17 +144,37 @@ pub struct PageScrollState {
     cursor_idx: Option<usize>,
     cursor_range: Option<Range<usize>>,
 
-    // Filter
-    filter: Option<crate::command::Command>,
+    // Filter layers
+    filters: FilterStack,
+    // Lines shown before and after each filter match
+    context: (usize, usize),
+    match_cache: RefCell<MatchCache>,
     // Search highlight
     pub search_query: Option<crate::command::Command>,
+    // Always on highlight rules, drawn under the search highlight
+    highlights: HighlightRules,
+    // Colors lines by their level when set
+    levels: Option<Arc<LevelDetector>>,
 
     // Match tracking
     matches: Vec<usize>,
+
+    // Show the side store of retained lines instead of the live pages
+    show_retained: bool,
+    // Draw folded duplicates once per occurrence instead of once with a badge
+    expand_folds: bool,
+    // Draw only the first line of multi-line records
+    collapse_records: bool,
+    // Draw structured lines as these fields
+    columns: Option<Columns>,
+    // Formats columns read fields with
+    formats: Option<Arc<FormatSet>>,
+
+    wrap_cache: RefCell<WrapCache>,
 }
 
 impl PageScrollState {
-    pub fn new(pages: Arc<RwLock<Pages>>) -> Self {
+    pub fn new(pages: SharedPages) -> Self {
         Self {
             pages,
             show_line_numbers: false,
@@ -52,24 +185,149 @@ impl PageScrollState {
             bottom_line_wrapped_skip: 0,
             cursor_idx: None,
             cursor_range: None,
-            filter: None,
+            filters: FilterStack::default(),
+            context: (0, 0),
+            match_cache: RefCell::new(MatchCache::default()),
             search_query: None,
+            highlights: HighlightRules::default(),
+            levels: None,
             matches: Vec::new(),
+            show_retained: false,
+            expand_folds: false,
+            collapse_records: false,
+            columns: None,
+            formats: None,
+            wrap_cache: RefCell::new(WrapCache::default()),
         }
     }
 
     pub fn set_size(&mut self, width: usize, height: usize) {
+        if self.width != width {
+            self.wrap_cache.get_mut().clear();
+        }
         self.width = width;
         self.height = height;
     }
 
     pub fn toggle_line_numbers(&mut self) {
         self.show_line_numbers = !self.show_line_numbers;
+        self.wrap_cache.get_mut().clear();
+    }
+
+    fn render_width(&self) -> usize {
+        let padding = if self.show_line_numbers { 6 } else { 0 };
+        self.width.saturating_sub(padding).max(1)
+    }
+
+    /// Wrapped segment ranges of line `idx`, served from the cache when possible.
+    ///
+    /// `line` is only projected to its columns on a miss. Only the first
+    /// physical line of a collapsed record is kept.
+    fn wrapped_ranges(&self, idx: usize, line: &str, width: usize) -> Arc<[Range<usize>]> {
+        let wrapped = self
+            .wrap_cache
+            .borrow_mut()
+            .get(idx, width, || self.display_text(line));
+        match wrapped.first_break {
+            Some(end) if self.collapse_records => wrapped
+                .ranges
+                .iter()
+                .filter(|r| r.end <= end)
+                .cloned()
+                .collect(),
+            _ => wrapped.ranges,
+        }
+    }
+
+    /// Screen rows taken by line `idx`, including a fold's badge row or its expanded copies.
+    fn rows(&self, pages: &Pages, idx: usize, line: &str) -> usize {
+        let wrapped = self.wrapped_ranges(idx, line, self.render_width()).len();
+        match pages.get_repeat(idx) {
+            Some(repeat) if self.expand_folds => wrapped * repeat.count,
+            Some(_) => wrapped + 1,
+            None => wrapped,
+        }
+    }
+
+    /// What is drawn for `line`, its chosen fields if columns are set.
+    fn display_text<'l>(&self, line: &'l str) -> Cow<'l, str> {
+        match self
+            .columns
+            .as_ref()
+            .and_then(|columns| columns.project(line, self.formats.as_deref()))
+        {
+            Some(text) => Cow::Owned(text),
+            None => Cow::Borrowed(line),
+        }
+    }
+
+    pub fn columns(&self) -> Option<&Columns> {
+        self.columns.as_ref()
+    }
+
+    /// Shows structured lines as `columns`, or as they are with `None`.
+    pub fn set_columns(&mut self, columns: Option<Columns>) {
+        self.columns = columns;
+        self.bottom_line_wrapped_skip = 0;
+        self.wrap_cache.get_mut().clear();
+        self.normalize_scroll();
+    }
+
+    /// Parses the fields shown as columns with `formats` too, see [`crate::format`].
+    pub fn set_formats(&mut self, formats: Option<Arc<FormatSet>>) {
+        self.formats = formats;
+        self.wrap_cache.get_mut().clear();
+        self.normalize_scroll();
+    }
+
+    /// Shows multi-line records as their first line only, or in full.
+    pub fn toggle_collapse_records(&mut self) {
+        self.collapse_records = !self.collapse_records;
+        self.bottom_line_wrapped_skip = 0;
+        self.normalize_scroll();
+    }
+
+    pub fn collapse_records(&self) -> bool {
+        self.collapse_records
+    }
+
+    /// Expands every fold into its lines, or folds them all again. Folds can't
+    /// be expanded one at a time.
+    pub fn toggle_expand_folds(&mut self) {
+        self.expand_folds = !self.expand_folds;
+        self.bottom_line_wrapped_skip = 0;
+        self.normalize_scroll();
+    }
+
+    /// Pages of the current view, either the live buffer or its retained lines.
+    pub fn snapshot(&self) -> Arc<Pages> {
+        let pages = self.pages.snapshot();
+        if !self.show_retained {
+            return pages;
+        }
+        pages.retained().cloned().unwrap_or_default()
+    }
+
+    /// Switches between the live buffer and the retained lines, starting at the bottom.
+    pub fn toggle_retained(&mut self) {
+        self.show_retained = !self.show_retained;
+        self.auto_scroll = true;
+        self.bottom_line_idx = 0;
+        self.bottom_line_wrapped_skip = 0;
+        self.cursor_idx = None;
+        self.cursor_range = None;
+        self.matches.clear();
+        self.wrap_cache.get_mut().clear();
+        self.match_cache.get_mut().lines.clear();
+    }
+
+    pub fn show_retained(&self) -> bool {
+        self.show_retained
     }
 
     pub fn toggle_autoscroll(&mut self) {
         if self.auto_scroll {
-            let pages_len = self.pages.read().unwrap().lines_count();
+            let pages_len = self.snapshot().lines_count();
             self.bottom_line_idx = pages_len.saturating_sub(1);
             self.bottom_line_wrapped_skip = 0;
         }
@@ -85,14 +343,7 @@ impl PageScrollState {
     }
 
     pub fn scroll_up(&mut self) {
-        let padding = if self.show_line_numbers { 6 } else { 0 };
-        let render_width = self.width.saturating_sub(padding).max(1);
-        if render_width == 0 {
-            return;
-        }
-
-        let pages_arc = self.pages.clone();
-        let pages_read = pages_arc.read().unwrap();
+        let pages_read = self.snapshot();
         let pages_len = pages_read.lines_count();
         if pages_len == 0 {
             return;
@@ -105,21 +356,16 @@ impl PageScrollState {
         }
 
         // Before scrolling up, check if we've already reached the top of the viewport
-        if is_top_reached_helper(
-            self.filter.as_ref(),
-            self.show_line_numbers,
-            self.width,
-            self.height,
-            self.auto_scroll,
+        if self.is_top_reached(
+            &pages_read,
             self.bottom_line_idx,
             self.bottom_line_wrapped_skip,
-            &*pages_read,
         ) {
             return;
         }
 
         if let Some(line) = pages_read.get_line(self.bottom_line_idx) {
-            let wrapped_count = get_wrapped_lines(line, render_width).len();
+            let wrapped_count = self.rows(&pages_read, self.bottom_line_idx, line);
             if self.bottom_line_wrapped_skip + 1 < wrapped_count {
                 self.bottom_line_wrapped_skip += 1;
             } else {
@@ -130,11 +376,7 @@ impl PageScrollState {
                 let mut it = pages_read.iter();
                 it.fast_skip_back(skip_from_back);
                 for (i, line) in it.enumerate().rev() {
-                    if self
-                        .filter
-                        .as_ref()
-                        .map_or(true, |f| f.is_match(line).is_some())
-                    {
+                    if self.line_kind(&pages_read, first_index + i, line).is_some() {
                         self.bottom_line_idx = first_index + i;
                         self.bottom_line_wrapped_skip = 0;
                         break;
@@ -147,8 +389,7 @@ impl PageScrollState {
     }
 
     pub fn scroll_down(&mut self) {
-        let pages_arc = self.pages.clone();
-        let pages_read = pages_arc.read().unwrap();
+        let pages_read = self.snapshot();
         let pages_len = pages_read.lines_count();
         if pages_len == 0 {
             return;
@@ -168,9 +409,8 @@ impl PageScrollState {
             it.fast_skip(skip);
             for (i, line) in it.enumerate() {
                 if self
-                    .filter
-                    .as_ref()
-                    .map_or(true, |f| f.is_match(line).is_some())
+                    .line_kind(&pages_read, first_index + skip + i, line)
+                    .is_some()
                 {
                     self.bottom_line_idx = first_index + skip + i;
                     break;
@@ -187,12 +427,11 @@ impl PageScrollState {
     }
 
     pub fn jump_to(&mut self, idx: usize) {
-        let pages_arc = self.pages.clone();
-        let pages_read = pages_arc.read().unwrap();
+        let pages_read = self.snapshot();
         let pages_len = pages_read.lines_count();
         if idx < pages_len {
             self.auto_scroll = false;
-            if !self.is_idx_visible_internal(&*pages_read, idx) {
+            if !self.is_idx_visible_internal(&pages_read, idx) {
                 self.bottom_line_idx = idx;
                 self.bottom_line_wrapped_skip = 0;
             }
@@ -204,12 +443,11 @@ impl PageScrollState {
     }
 
     pub fn jump_to_with_range(&mut self, idx: usize, range: Range<usize>) {
-        let pages_arc = self.pages.clone();
-        let pages_read = pages_arc.read().unwrap();
+        let pages_read = self.snapshot();
         let pages_len = pages_read.lines_count();
         if idx < pages_len {
             self.auto_scroll = false;
-            if !self.is_idx_visible_internal(&*pages_read, idx) {
+            if !self.is_idx_visible_internal(&pages_read, idx) {
                 self.bottom_line_idx = idx;
                 self.bottom_line_wrapped_skip = 0;
             }
@@ -231,12 +469,69 @@ impl PageScrollState {
         self.bottom_line_idx
     }
 
-    pub fn set_filter(&mut self, filter: Option<crate::command::Command>) {
-        self.filter = filter;
+    pub fn filters(&self) -> &FilterStack {
+        &self.filters
     }
 
-    pub fn filter(&self) -> Option<&crate::command::Command> {
-        self.filter.as_ref()
+    pub fn filters_mut(&mut self) -> &mut FilterStack {
+        self.match_cache.get_mut().lines.clear();
+        &mut self.filters
+    }
+
+    /// The filter layers if any of them is enabled.
+    fn active_filter(&self) -> Option<&FilterStack> {
+        Some(&self.filters).filter(|filters| filters.is_active())
+    }
+
+    /// Lines shown before and after each filter match, like grep's `-B` and `-A`.
+    pub fn context(&self) -> (usize, usize) {
+        self.context
+    }
+
+    pub fn set_context(&mut self, before: usize, after: usize) {
+        self.context = (before, after);
+        self.normalize_scroll();
+    }
+
+    /// Whether line `idx` is shown, and why. Without a filter every line matches.
+    fn line_kind(&self, pages: &Pages, idx: usize, line: &str) -> Option<LineKind> {
+        let Some(filter) = self.active_filter() else {
+            return Some(LineKind::Match);
+        };
+        let mut cache = self.match_cache.borrow_mut();
+        if cache.get(pages, idx, line, filter) {
+            return Some(LineKind::Match);
+        }
+
+        // A match at most `after` lines above, or at most `before` lines below
+        let (before, after) = self.context;
+        let above = idx.saturating_sub(after).max(pages.first_index())..idx;
+        let below = idx + 1..(idx + 1 + before).min(pages.lines_count());
+        let near_match = above.chain(below).any(|i| {
+            pages
+                .get_line(i)
+                .is_some_and(|line| cache.get(pages, i, line, filter))
+        });
+        near_match.then_some(LineKind::Context)
+    }
+
+    /// Separator rows drawn between line `idx` and the shown line below it.
+    fn separator_rows(&self, idx: usize, below: Option<usize>) -> usize {
+        let context = self.context != (0, 0) && self.active_filter().is_some();
+        usize::from(context && below.is_some_and(|below| below > idx + 1))
+    }
+
+    pub fn highlights(&self) -> &HighlightRules {
+        &self.highlights
+    }
+
+    pub fn highlights_mut(&mut self) -> &mut HighlightRules {
+        &mut self.highlights
+    }
+
+    /// Colors lines by the level `levels` finds in them.
+    pub fn set_levels(&mut self, levels: Option<Arc<LevelDetector>>) {
+        self.levels = levels;
     }
 
     pub fn set_search_query(&mut self, query: Option<crate::command::Command>) {
@@ -257,6 +552,8 @@ impl PageScrollState {
 
     pub fn remove_matches_before(&mut self, idx: usize) {
         self.matches.retain(|&m| m >= idx);
+        self.wrap_cache.get_mut().remove_before(idx);
+        self.match_cache.get_mut().lines.retain(|&i, _| i >= idx);
     }
 
     pub fn get_match_status(&self) -> Option<(usize, usize)> {
@@ -264,7 +561,6 @@ impl PageScrollState {
             return None;
         }
 
-        let total = self.matches.len();
         let current_pos = self.cursor_idx.or(Some(self.bottom_line_idx))?;
 
         // Find the rank of the current_pos among matches.
@@ -284,6 +580,11 @@ impl PageScrollState {
             }
         };
 
+        // Folded duplicates count once per occurrence
+        let pages = self.snapshot();
+        let rank = pages.count_occurrences(&self.matches[..rank]);
+        let total = pages.count_occurrences(&self.matches);
+
         Some((rank, total))
     }
 
@@ -291,9 +592,11 @@ impl PageScrollState {
         self.cursor_idx
     }
 
+    pub fn cursor_range(&self) -> Option<&Range<usize>> {
+        self.cursor_range.as_ref()
+    }
+
     fn is_idx_visible_internal(&self, pages: &Pages, target_idx: usize) -> bool {
-        let padding = if self.show_line_numbers { 6 } else { 0 };
-        let render_width = self.width.saturating_sub(padding).max(1);
         if self.height == 0 {
             return false;
         }
@@ -319,22 +622,21 @@ impl PageScrollState {
             self.bottom_line_wrapped_skip
         };
         let mut total_rendered_lines = 0;
+        let mut below = None;
         let skip_from_back = pages_len.saturating_sub(end_idx + 1);
 
         let mut it = pages.iter();
         it.fast_skip_back(skip_from_back);
         for (i, line_content) in it.enumerate().rev() {
             let current_idx = pages.first_index() + i;
-            if self
-                .filter
-                .as_ref()
-                .map_or(true, |f| f.is_match(line_content).is_some())
-            {
+            if self.line_kind(pages, current_idx, line_content).is_some() {
+                total_rendered_lines += self.separator_rows(current_idx, below);
+                below = Some(current_idx);
                 if current_idx == target_idx {
-                    return true;
+                    return total_rendered_lines < self.height;
                 }
 
-                let wrapped_len = get_wrapped_lines(line_content, render_width).len();
+                let wrapped_len = self.rows(pages, current_idx, line_content);
                 let effective_lines = wrapped_len.saturating_sub(skip_sublines);
 
                 total_rendered_lines += effective_lines;
@@ -354,25 +656,14 @@ impl PageScrollState {
             return;
         }
 
-        // Clone Arc to decouple borrow from self
-        let pages_arc = self.pages.clone();
-        let pages = pages_arc.read().unwrap();
+        let pages = self.snapshot();
 
         let mut current_bottom_idx = self.bottom_line_idx;
         let mut current_wrapped_skip = self.bottom_line_wrapped_skip;
 
         // While the top of the file is visible and there's potentially more to show at the bottom,
         // scroll down (increase bottom_line_idx) to fill the gap.
-        while is_top_reached_helper(
-            self.filter.as_ref(),
-            self.show_line_numbers,
-            self.width,
-            self.height,
-            self.auto_scroll,
-            current_bottom_idx,
-            current_wrapped_skip,
-            &*pages,
-        ) {
+        while self.is_top_reached(&pages, current_bottom_idx, current_wrapped_skip) {
             let pages_len = pages.lines_count();
             if current_bottom_idx + 1 >= pages_len {
                 break;
@@ -386,9 +677,8 @@ impl PageScrollState {
             let mut found = false;
             for (i, line) in it.enumerate() {
                 if self
-                    .filter
-                    .as_ref()
-                    .map_or(true, |f| f.is_match(line).is_some())
+                    .line_kind(&pages, first_index + skip + i, line)
+                    .is_some()
                 {
                     current_bottom_idx = first_index + skip + i;
                     current_wrapped_skip = 0;
@@ -405,70 +695,120 @@ impl PageScrollState {
         self.bottom_line_idx = current_bottom_idx;
         self.bottom_line_wrapped_skip = current_wrapped_skip;
     }
-}
 
-fn is_top_reached_helper(
-    filter: Option<&crate::command::Command>,
-    show_line_numbers: bool,
-    width: usize,
-    height: usize,
-    auto_scroll: bool,
-    bottom_line_idx: usize,
-    bottom_line_wrapped_skip: usize,
-    pages: &Pages,
-) -> bool {
-    let padding = if show_line_numbers { 6 } else { 0 };
-    let render_width = width.saturating_sub(padding).max(1);
-    if height == 0 {
-        return false;
-    }
-
-    let pages_len = pages.lines_count();
-    if pages_len == 0 {
-        return true;
-    }
-
-    let end_idx = if auto_scroll {
-        pages_len.saturating_sub(1)
-    } else {
-        bottom_line_idx.min(pages_len.saturating_sub(1))
-    };
-
-    let mut skip_sublines = if auto_scroll {
-        0
-    } else {
-        bottom_line_wrapped_skip
-    };
-
-    let mut total_rendered_lines = 0;
-    let skip_from_back = pages_len.saturating_sub(end_idx + 1);
-
-    let mut it = pages.iter();
-    it.fast_skip_back(skip_from_back);
-    for line_content in it.rev() {
-        if filter.map_or(true, |f| f.is_match(line_content).is_some()) {
-            let wrapped_len = get_wrapped_lines(line_content, render_width).len();
-            let effective_lines = wrapped_len.saturating_sub(skip_sublines);
-
-            total_rendered_lines += effective_lines;
-
-            if total_rendered_lines >= height {
-                return false; // Viewport is full
+    fn is_top_reached(
+        &self,
+        pages: &Pages,
+        bottom_line_idx: usize,
+        bottom_line_wrapped_skip: usize,
+    ) -> bool {
+        if self.height == 0 {
+            return false;
+        }
+
+        let pages_len = pages.lines_count();
+        if pages_len == 0 {
+            return true;
+        }
+
+        let end_idx = if self.auto_scroll {
+            pages_len.saturating_sub(1)
+        } else {
+            bottom_line_idx.min(pages_len.saturating_sub(1))
+        };
+
+        let mut skip_sublines = if self.auto_scroll {
+            0
+        } else {
+            bottom_line_wrapped_skip
+        };
+
+        let mut total_rendered_lines = 0;
+        let mut below = None;
+        let first_index = pages.first_index();
+        let skip_from_back = pages_len.saturating_sub(end_idx + 1);
+
+        let mut it = pages.iter();
+        it.fast_skip_back(skip_from_back);
+        for (i, line_content) in it.enumerate().rev() {
+            let current_idx = first_index + i;
+            if self.line_kind(pages, current_idx, line_content).is_some() {
+                total_rendered_lines += self.separator_rows(current_idx, below);
+                below = Some(current_idx);
+                let wrapped_len = self.rows(pages, current_idx, line_content);
+                let effective_lines = wrapped_len.saturating_sub(skip_sublines);
+
+                total_rendered_lines += effective_lines;
+
+                if total_rendered_lines >= self.height {
+                    return false; // Viewport is full
+                }
             }
+            skip_sublines = 0;
         }
-        skip_sublines = 0;
+
+        // If we've processed all matching lines and viewport is not full, the top is reached
+        total_rendered_lines < self.height
     }
+}
+
+/// One screen row: a wrapped segment of a line, the badge under a fold, or
+/// the separator between groups of context lines.
+struct RenderRow<'a> {
+    idx: usize,
+    /// Number shown in the gutter, `None` for badge rows
+    number: Option<usize>,
+    text: Cow<'a, str>,
+    source_range: Range<usize>,
+    /// Shows the chosen fields instead of the line
+    projected: bool,
+    /// Lines of a collapsed record left out after this row
+    hidden_lines: usize,
+    /// Shown only for being near a filter match
+    context: bool,
+    /// Colors the row when nothing else does
+    level: Option<Level>,
+    /// Match ranges of the whole line, not just this row
+    highlights: Vec<Range<usize>>,
+    /// Styled ranges of logfmt fields and highlight rules, also of the whole line
+    rule_spans: Vec<(Range<usize>, Style)>,
+}
 
-    // If we've processed all matching lines and viewport is not full, the top is reached
-    total_rendered_lines < height
+/// Dims the keys and brightens the values of a logfmt line, nothing for other lines.
+fn logfmt_spans(line: &str) -> Vec<(Range<usize>, Style)> {
+    let key_style = Style::default().fg(ratatui::style::Color::DarkGray);
+    let value_style = Style::default().add_modifier(ratatui::style::Modifier::BOLD);
+    parse_logfmt(line)
+        .unwrap_or_default()
+        .into_iter()
+        .flat_map(|field| {
+            let key = field.key_range.map(|range| (range, key_style));
+            let value = field.range.map(|range| (range, value_style));
+            key.into_iter().chain(value)
+        })
+        .collect()
 }
 
+/// Draws a [`PageScrollState`] as it is, with the size it was last given.
 pub struct PageScrollWidget<'a>(pub &'a PageScrollState);
 
+/// Stateful version of [`PageScrollWidget`] that resizes the state to the
+/// area it is rendered into.
+pub struct PageScroll;
+
+impl StatefulWidget for PageScroll {
+    type State = PageScrollState;
+
+    fn render(self, area: Rect, buf: &mut Buffer, state: &mut Self::State) {
+        state.set_size(area.width as usize, area.height as usize);
+        PageScrollWidget(state).render(area, buf);
+    }
+}
+
 impl<'a> Widget for PageScrollWidget<'a> {
-    fn render(self, area: ratatui::prelude::Rect, buf: &mut Buffer) {
+    fn render(self, area: Rect, buf: &mut Buffer) {
         let state = self.0;
-        let pages = state.pages.read().unwrap();
+        let pages = state.snapshot();
         let pages_len = pages.lines_count();
         if pages_len == 0 {
             return;
@@ -498,192 +838,285 @@ impl<'a> Widget for PageScrollWidget<'a> {
 
         let first_index = pages.first_index();
         let skip_from_back = pages_len.saturating_sub(end_idx + 1);
+        let mut below = None;
 
         let mut it = pages.iter();
         it.fast_skip_back(skip_from_back);
         'outer: for (i, line_content) in it.enumerate().rev() {
             let current_idx = first_index + i;
-            let mut highlight = None;
-            if let Some(filter) = &state.filter {
-                if let Some(mat) = filter.is_match(line_content) {
-                    highlight = Some(mat);
-                } else {
-                    continue;
+            let Some(kind) = state.line_kind(&pages, current_idx, line_content) else {
+                continue;
+            };
+            if state.separator_rows(current_idx, below) > 0 {
+                lines_to_render.push(RenderRow {
+                    idx: current_idx,
+                    number: None,
+                    text: Cow::Borrowed("--"),
+                    source_range: 0..0,
+                    projected: false,
+                    hidden_lines: 0,
+                    context: false,
+                    level: None,
+                    highlights: Vec::new(),
+                    rule_spans: Vec::new(),
+                });
+                if lines_to_render.len() >= height {
+                    break 'outer;
                 }
             }
+            below = Some(current_idx);
+
+            let level = state.levels.as_ref().and_then(|d| d.detect(line_content));
+            let display = state.display_text(line_content);
+            let projected = matches!(display, Cow::Owned(_));
+
+            let mut highlights = Vec::new();
+            if let Some(filter) = state.active_filter() {
+                highlights = filter.match_ranges(&display);
+            }
 
-            // If no filter highlight, check if search_query matches
-            if highlight.is_none() {
+            // If no filter highlight, highlight the search matches
+            if highlights.is_empty() {
                 if let Some(search) = &state.search_query {
-                    highlight = search.is_match(line_content);
+                    highlights = search.match_ranges(&display);
                 }
             }
+            let mut rule_spans = logfmt_spans(&display);
+            rule_spans.extend(state.highlights.spans(&display));
+
+            let number = pages.line_number(current_idx);
+            let repeat = pages.get_repeat(current_idx);
+            let copies = match repeat {
+                Some(repeat) if state.expand_folds => repeat.count,
+                _ => 1,
+            };
 
-            let wrapped = get_wrapped_lines(line_content, render_width);
-            for (w, source_range) in wrapped.into_iter().rev() {
+            if let (Some(repeat), false) = (repeat, state.expand_folds) {
                 if skip_sublines > 0 {
                     skip_sublines -= 1;
-                    continue;
+                } else {
+                    let first = pages.get_time(current_idx).unwrap_or_default();
+                    let badge = format!(
+                        "  ×{} first {} last {}",
+                        repeat.count,
+                        format_millis(first),
+                        format_millis(repeat.last_time)
+                    );
+                    lines_to_render.push(RenderRow {
+                        idx: current_idx,
+                        number: None,
+                        text: Cow::Owned(badge),
+                        source_range: 0..0,
+                        projected: false,
+                        hidden_lines: 0,
+                        context: false,
+                        level: None,
+                        highlights: Vec::new(),
+                        rule_spans: Vec::new(),
+                    });
+                    if lines_to_render.len() >= height {
+                        break 'outer;
+                    }
                 }
-                lines_to_render.push((current_idx, w, source_range, highlight.clone()));
-                if lines_to_render.len() >= height {
-                    break 'outer;
+            }
+
+            let wrapped = state.wrapped_ranges(current_idx, line_content, render_width);
+            let hidden_lines = if state.collapse_records {
+                display.matches('\n').count()
+            } else {
+                0
+            };
+            for copy in (0..copies).rev() {
+                for (segment, source_range) in wrapped.iter().enumerate().rev() {
+                    if skip_sublines > 0 {
+                        skip_sublines -= 1;
+                        continue;
+                    }
+                    let last_segment = segment + 1 == wrapped.len();
+                    lines_to_render.push(RenderRow {
+                        idx: current_idx,
+                        number: Some(number + copy),
+                        text: match &display {
+                            Cow::Borrowed(line) => Cow::Borrowed(&line[source_range.clone()]),
+                            Cow::Owned(line) => Cow::Owned(line[source_range.clone()].to_string()),
+                        },
+                        source_range: source_range.clone(),
+                        projected,
+                        hidden_lines: if last_segment { hidden_lines } else { 0 },
+                        context: kind == LineKind::Context,
+                        level,
+                        highlights: highlights.clone(),
+                        rule_spans: rule_spans.clone(),
+                    });
+                    if lines_to_render.len() >= height {
+                        break 'outer;
+                    }
                 }
             }
         }
         lines_to_render.reverse();
 
-        for (i, (idx, line, source_range, filter_highlight)) in lines_to_render.iter().enumerate() {
+        for (i, row) in lines_to_render.iter().enumerate() {
             if i >= height {
                 break;
             }
 
             let y = area.y + i as u16;
-            let style = if Some(*idx) == state.cursor_idx && state.cursor_range.is_none() {
+            let Some(number) = row.number else {
+                buf.set_string(
+                    area.x + padding as u16,
+                    y,
+                    &row.text,
+                    Style::default().fg(ratatui::style::Color::DarkGray),
+                );
+                continue;
+            };
+            let is_cursor = Some(row.idx) == state.cursor_idx;
+            // The occurrence is an offset into the line, not its columns
+            let cursor_range = state.cursor_range.as_ref().filter(|_| !row.projected);
+            let style = if is_cursor && cursor_range.is_none() {
                 Style::default().fg(ratatui::style::Color::Yellow)
+            } else if row.context {
+                Style::default().fg(ratatui::style::Color::DarkGray)
             } else {
-                Style::default()
+                match row.level.and_then(|level| level.color()) {
+                    Some(color) => Style::default().fg(color),
+                    None => Style::default(),
+                }
             };
 
             if state.show_line_numbers {
-                let line_num = format!("[{}]", idx);
+                let line_num = format!("[{}]", number);
                 let num_padding = 5usize.saturating_sub(line_num.len());
                 buf.set_string(area.x + num_padding as u16, y, &line_num, style);
+            }
 
-                if let Some(range) = state.cursor_range.as_ref() {
-                    if Some(*idx) == state.cursor_idx {
-                        let green_style = Style::default()
-                            .bg(ratatui::style::Color::Green)
-                            .fg(ratatui::style::Color::Black);
-
-                        self.render_line_partial(
-                            buf,
-                            area.x + padding as u16,
-                            y,
-                            line,
-                            source_range,
-                            range,
-                            green_style,
-                        );
-                        continue;
-                    }
+            let yellow_style = Style::default()
+                .bg(ratatui::style::Color::Yellow)
+                .fg(ratatui::style::Color::Black);
+            let green_style = Style::default()
+                .bg(ratatui::style::Color::Green)
+                .fg(ratatui::style::Color::Black);
+
+            // On top of the highlight rules, every match is yellow, except on the
+            // cursor line where the current occurrence, or every match if there
+            // is none, is green
+            let mut spans = row.rule_spans.clone();
+            for range in &row.highlights {
+                let current = is_cursor && cursor_range.is_none_or(|c| c == range);
+                if !current {
+                    spans.push((range.clone(), yellow_style));
                 }
-
-                if let Some(range) = filter_highlight {
-                    let highlight_style = if Some(*idx) == state.cursor_idx {
-                        Style::default()
-                            .bg(ratatui::style::Color::Green)
-                            .fg(ratatui::style::Color::Black)
-                    } else {
-                        Style::default()
-                            .bg(ratatui::style::Color::Yellow)
-                            .fg(ratatui::style::Color::Black)
-                    };
-
-                    self.render_line_partial(
-                        buf,
-                        area.x + padding as u16,
-                        y,
-                        line,
-                        source_range,
-                        range,
-                        highlight_style,
-                    );
-                } else {
-                    buf.set_string(area.x + padding as u16, y, line, style);
-                }
-            } else {
-                if let Some(range) = state.cursor_range.as_ref() {
-                    if Some(*idx) == state.cursor_idx {
-                        let green_style = Style::default()
-                            .bg(ratatui::style::Color::Green)
-                            .fg(ratatui::style::Color::Black);
-                        self.render_line_partial(
-                            buf,
-                            area.x,
-                            y,
-                            line,
-                            source_range,
-                            range,
-                            green_style,
-                        );
-                        continue;
-                    }
+            }
+            if is_cursor {
+                match cursor_range {
+                    Some(range) => spans.push((range.clone(), green_style)),
+                    None => spans.extend(
+                        row.highlights
+                            .iter()
+                            .map(|range| (range.clone(), green_style)),
+                    ),
                 }
+            }
 
-                if let Some(range) = filter_highlight {
-                    let highlight_style = if Some(*idx) == state.cursor_idx {
-                        Style::default()
-                            .bg(ratatui::style::Color::Green)
-                            .fg(ratatui::style::Color::Black)
-                    } else {
-                        Style::default()
-                            .bg(ratatui::style::Color::Yellow)
-                            .fg(ratatui::style::Color::Black)
-                    };
-                    self.render_line_partial(
-                        buf,
-                        area.x,
-                        y,
-                        line,
-                        source_range,
-                        range,
-                        highlight_style,
-                    );
-                } else {
-                    buf.set_string(area.x, y, line, style);
-                }
+            let x = area.x + padding as u16;
+            Self::render_line_spans(buf, x, y, &row.text, &row.source_range, &spans, style);
+            if row.hidden_lines > 0 {
+                buf.set_string(
+                    x + row.text.chars().count() as u16,
+                    y,
+                    format!(" [+{} lines]", row.hidden_lines),
+                    Style::default().fg(ratatui::style::Color::DarkGray),
+                );
             }
         }
     }
 }
 
 impl<'a> PageScrollWidget<'a> {
-    fn render_line_partial(
-        &self,
+    /// Draws one row of a line, styling the parts covered by `spans`.
+    ///
+    /// Span ranges are byte offsets into the whole line, later spans are drawn
+    /// over earlier ones where they overlap.
+    fn render_line_spans(
         buf: &mut Buffer,
         x: u16,
         y: u16,
         segment_text: &str,
         segment_range: &Range<usize>,
-        highlight_range: &Range<usize>,
-        highlight_style: Style,
+        spans: &[(Range<usize>, Style)],
+        base_style: Style,
     ) {
-        // Calculate the intersection of highlight_range and segment_range
-        let intersect_start = highlight_range.start.max(segment_range.start);
-        let intersect_end = highlight_range.end.min(segment_range.end);
-
-        if intersect_start < intersect_end {
-            // There is an intersection. Highlight only the intersected part.
-            // Indices relative to the segment_text
+        buf.set_string(x, y, segment_text, base_style);
+        for (range, style) in spans {
+            // Only the part of the span that falls on this row
+            let intersect_start = range.start.max(segment_range.start);
+            let intersect_end = range.end.min(segment_range.end);
+            if intersect_start >= intersect_end {
+                continue;
+            }
             let rel_start = intersect_start - segment_range.start;
             let rel_end = intersect_end - segment_range.start;
-
-            // Draw before highlight
-            if rel_start > 0 {
-                buf.set_string(x, y, &segment_text[..rel_start], Style::default());
-            }
-
-            // Draw highlight
             buf.set_string(
                 x + rel_start as u16,
                 y,
                 &segment_text[rel_start..rel_end],
-                highlight_style,
+                *style,
             );
-
-            // Draw after highlight
-            if rel_end < segment_text.len() {
-                buf.set_string(
-                    x + rel_end as u16,
-                    y,
-                    &segment_text[rel_end..],
-                    Style::default(),
-                );
-            }
-        } else {
-            // No intersection
-            buf.set_string(x, y, segment_text, Style::default());
         }
     }
 }
+
+#[test]
+fn test_wrap_cache_width_change() {
+    let mut cache = WrapCache::default();
+    let line = "aaaa bbbb cccc";
+
+    assert_eq!(cache.get(0, 20, || line.into()).ranges.len(), 1);
+    // A hit doesn't ask for the text again
+    assert_eq!(cache.get(0, 20, || unreachable!()).ranges.len(), 1);
+    assert_eq!(cache.lines.len(), 1);
+
+    // A different width must not serve the stale layout
+    let ranges = cache.get(0, 5, || line.into()).ranges;
+    assert_eq!(ranges.len(), 3);
+    assert_eq!(&line[ranges[1].clone()], "bbbb");
+
+    cache.remove_before(1);
+    assert!(cache.lines.is_empty());
+}
+
+#[test]
+fn test_filter_context_lines() {
+    use crate::command::{Command, SearchPattern};
+    use crate::filter::FilterLayer;
+    use crate::pages::PagesWriter;
+
+    let mut lines = Pages::new(1024, 4);
+    lines.extend(["a", "b", "err", "c", "d", "e", "f", "err", "g", "h"]);
+    let (_writer, pages) = PagesWriter::new(lines);
+
+    let mut state = PageScrollState::new(pages);
+    state
+        .filters_mut()
+        .push(FilterLayer::new(Command::SearchFor(
+            SearchPattern::Substring("err".to_string()),
+        )));
+    state.set_context(1, 1);
+    state.set_size(10, 6);
+
+    let area = Rect::new(0, 0, 10, 6);
+    let mut buf = Buffer::empty(area);
+    PageScrollWidget(&state).render(area, &mut buf);
+    let rows: Vec<String> = (0..6)
+        .map(|y| (0..10).map(|x| buf[(x, y)].symbol()).collect::<String>())
+        .map(|row| row.trim_end().to_string())
+        .collect();
+    assert_eq!(rows, ["err", "c", "--", "f", "err", "g"]);
+
+    // Seven rows including the separator, which must count towards the top
+    state.set_size(10, 7);
+    assert!(!state.is_top_reached(&state.snapshot(), 9, 0));
+    state.set_size(10, 8);
+    assert!(state.is_top_reached(&state.snapshot(), 9, 0));
+}
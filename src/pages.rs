@@ -1,12 +1,155 @@
 use crate::command::Matcher;
+use crate::format::FormatSet;
+use crate::session::Session;
+use regex::Regex;
 use std::collections::VecDeque;
-use std::ops::Index;
+use std::ops::{Index, Range};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex,
+};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Milliseconds since the unix epoch, the unit of line arrival times.
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+/// How consecutive duplicate lines are folded into a single entry.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Collapse {
+    /// Keep every line
+    #[default]
+    Off,
+    /// Fold lines identical to the previous one
+    Exact,
+    /// Fold lines equal to the previous one after ignoring numbers, month and weekday names and
+    /// AM/PM, so counters and timestamps can differ
+    Fuzzy,
+}
+
+impl Collapse {
+    fn is_repeat(self, prev: &str, line: &str) -> bool {
+        match self {
+            Collapse::Off => false,
+            Collapse::Exact => prev == line,
+            Collapse::Fuzzy => eq_ignoring_timestamps(prev, line),
+        }
+    }
+}
 
+/// Words that differ between timestamps, `Oct`, `Monday` or `PM`, grouped so
+/// only words of the same kind are taken as equal.
+const TIME_WORDS: [&str; 3] = [
+    "jan feb mar apr may jun jul aug sep oct nov dec january february march april june july \
+     august september october november december",
+    "mon tue wed thu fri sat sun monday tuesday wednesday thursday friday saturday sunday",
+    "am pm",
+];
+
+fn time_word_kind(word: &[u8]) -> Option<usize> {
+    TIME_WORDS.iter().position(|words| {
+        words
+            .split_whitespace()
+            .any(|name| name.as_bytes().eq_ignore_ascii_case(word))
+    })
+}
+
+/// Compares strings with every run of ascii digits treated as equal, and the
+/// month and weekday names and AM/PM of timestamps too.
+fn eq_ignoring_timestamps(a: &str, b: &str) -> bool {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    let run_end = |s: &[u8], start: usize, class: fn(&u8) -> bool| {
+        start + s[start..].iter().take_while(|&c| class(c)).count()
+    };
+    let (mut i, mut j) = (0, 0);
+    loop {
+        match (a.get(i), b.get(j)) {
+            (None, None) => return true,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                i = run_end(a, i, u8::is_ascii_digit);
+                j = run_end(b, j, u8::is_ascii_digit);
+            }
+            (Some(x), Some(y)) if x.is_ascii_alphabetic() && y.is_ascii_alphabetic() => {
+                let (end_a, end_b) = (
+                    run_end(a, i, u8::is_ascii_alphabetic),
+                    run_end(b, j, u8::is_ascii_alphabetic),
+                );
+                let (word_a, word_b) = (&a[i..end_a], &b[j..end_b]);
+                if word_a != word_b {
+                    let kind = time_word_kind(word_a);
+                    if kind.is_none() || kind != time_word_kind(word_b) {
+                        return false;
+                    }
+                }
+                (i, j) = (end_a, end_b);
+            }
+            (Some(x), Some(y)) if x == y => (i, j) = (i + 1, j + 1),
+            _ => return false,
+        }
+    }
+}
+
+/// Page size of the side store holding lines kept by `Retention::retain`.
+const RETAINED_PAGE_CAPACITY: usize = 16 * 1024;
+
+/// Local wall clock time of `ms` (milliseconds since the unix epoch) as `HH:MM:SS`.
+pub fn format_millis(ms: u64) -> String {
+    chrono::DateTime::from_timestamp_millis(ms as i64)
+        .map(|t| {
+            t.with_timezone(&chrono::Local)
+                .format("%H:%M:%S")
+                .to_string()
+        })
+        .unwrap_or_default()
+}
+
+/// Limits on retained history, applied on top of the page budget.
+///
+/// Whichever limit is hit first evicts the oldest lines. Evicted lines
+/// matching `retain` are copied to a side store of `retain_size` bytes.
+#[derive(Debug, Default, Clone)]
+pub struct Retention {
+    pub max_lines: Option<usize>,
+    pub max_age: Option<Duration>,
+    pub retain: Option<Regex>,
+    pub retain_size: usize,
+}
+
+/// Ring buffer of lines split into fixed capacity pages.
+///
+/// Pages are shared behind `Arc`, so cloning `Pages` is cheap and yields an
+/// immutable snapshot. Appending only copies the newest page if a snapshot
+/// still holds it.
+///
+/// Lines are addressed by a global index that never changes once assigned:
+///
+/// - `first_index() <= lines_count()`, and neither ever decreases.
+/// - `get_line(idx)` is `Some` exactly for `idx` in `first_index()..lines_count()`.
+/// - Eviction only raises `first_index()`, appends only raise `lines_count()`.
+/// - With [`Collapse`] on, an index is an entry that may stand for several
+///   received lines, `line_number(idx)` counts every one of them.
+#[derive(Clone)]
 pub struct Pages {
-    pages: VecDeque<Page>,
+    pages: VecDeque<Arc<Page>>,
     page_capacity: usize,
     max_pages: usize,
+    /// Lines longer than this are truncated with a marker before being stored,
+    /// the whole budget unless capped with `with_max_line_size`
+    max_line_size: usize,
     global_offset: usize,
+    /// Lines at the start of the front page that were already evicted
+    front_skip: usize,
+    /// Lines held, kept up to date so appends don't walk every page
+    held: usize,
+    retention: Retention,
+    /// Evicted lines that matched `Retention::retain`, numbered as they were here
+    retained: Option<Arc<Pages>>,
+    collapse: Collapse,
+    /// Number of the next line received, counting every folded repeat
+    next_number: usize,
 }
 
 impl Default for Pages {
@@ -18,67 +161,326 @@ impl Default for Pages {
 impl Pages {
     pub fn new(page_capacity: usize, page_count: usize) -> Self {
         let mut pages = VecDeque::with_capacity(page_count);
-        pages.push_back(Page::with_capacity(page_capacity));
+        pages.push_back(Arc::new(Page::with_capacity(page_capacity)));
         Self {
             page_capacity,
             max_pages: page_count,
+            max_line_size: page_capacity * page_count,
             pages,
             global_offset: 0,
+            front_skip: 0,
+            held: 0,
+            retention: Retention::default(),
+            retained: None,
+            collapse: Collapse::Off,
+            next_number: 0,
+        }
+    }
+
+    /// Folds consecutive duplicates into one entry. Entries are then numbered
+    /// by the lines received, so numbers skip ahead after a fold.
+    pub fn with_collapse(mut self, collapse: Collapse) -> Self {
+        self.collapse = collapse;
+        self
+    }
+
+    pub fn with_retention(mut self, retention: Retention) -> Self {
+        if retention.retain.is_some() {
+            let count = retention
+                .retain_size
+                .div_ceil(RETAINED_PAGE_CAPACITY)
+                .max(1);
+            self.retained = Some(Arc::new(Pages::new(RETAINED_PAGE_CAPACITY, count)));
+        }
+        self.retention = retention;
+        self
+    }
+
+    /// Caps the size of a single line, clamped to the total budget.
+    ///
+    /// Longer lines lose their end for good, there is nothing to expand them
+    /// from, so by default only lines beyond the whole budget are cut.
+    pub fn with_max_line_size(mut self, max_line_size: usize) -> Self {
+        self.max_line_size = max_line_size.min(self.budget()).max(1);
+        self
+    }
+
+    /// Total bytes all pages may allocate.
+    fn budget(&self) -> usize {
+        self.page_capacity * self.max_pages
+    }
+
+    /// Bytes allocated by the pages currently held.
+    fn allocated(&self) -> usize {
+        self.pages.iter().map(|page| page.capacity).sum()
+    }
+
+    /// Side store of lines kept by `Retention::retain` after eviction.
+    pub fn retained(&self) -> Option<&Arc<Pages>> {
+        self.retained.as_ref()
+    }
+
+    /// Number to display for line `idx`, which differs from `idx` for retained lines.
+    pub fn line_number(&self, idx: usize) -> usize {
+        self.locate(idx)
+            .and_then(|(page, local)| page.number_at(local))
+            .unwrap_or(idx)
+    }
+
+    /// Index of the line numbered `number`, or of the closest one before it
+    /// when it was folded or not retained. `None` outside the lines held.
+    pub fn index_of_number(&self, number: usize) -> Option<usize> {
+        let end = self.lines_count();
+        // Numbers grow with the index
+        let (mut low, mut high) = (self.global_offset, end);
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if self.line_number(mid) <= number {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        let idx = low
+            .checked_sub(1)
+            .filter(|&idx| idx >= self.global_offset)?;
+        let span = self.get_repeat(idx).map_or(1, |repeat| repeat.count);
+        let past_end = idx + 1 == end && number >= self.line_number(idx) + span;
+        (!past_end).then_some(idx)
+    }
+
+    fn add_numbered_line(&mut self, s: &str, time: u64, number: usize) {
+        self.add_line_at(s, time);
+        if let Some(back) = self.pages.back_mut() {
+            Arc::make_mut(back).set_last_number(number);
         }
     }
 
     pub fn add_line(&mut self, s: &str) {
-        if self.pages.back_mut().unwrap().add_str_only_if_in_cap(s) {
-            return;
+        self.add_line_at(s, now_millis());
+    }
+
+    /// Adds a line that arrived at `time` (milliseconds since the unix epoch).
+    ///
+    /// A line longer than a page gets a dedicated page sized to fit it, which
+    /// counts against the budget like any other page. Lines beyond
+    /// `max_line_size` are truncated first.
+    pub fn add_line_at(&mut self, s: &str, time: u64) {
+        let truncated;
+        let s = if s.len() > self.max_line_size {
+            truncated = truncate_line(s, self.max_line_size);
+            truncated.as_str()
+        } else {
+            s
+        };
+
+        if self.collapse != Collapse::Off && self.held > 0 {
+            let back = self.pages.back_mut().unwrap();
+            let last = &back[back.len() - 1];
+            if self.collapse.is_repeat(last, s) {
+                Arc::make_mut(back).add_repeat(time);
+                self.next_number += 1;
+                return;
+            }
         }
 
-        if self.pages.len() == self.max_pages {
-            let mut page = self.pages.pop_front().unwrap();
-            self.global_offset += page.len();
-            page.clear();
-            page.add_str(s);
-            self.pages.push_back(page);
+        let back = self.pages.back_mut().unwrap();
+        if back.fits(s) {
+            Arc::make_mut(back).add_str(s, time);
         } else {
-            let mut page = Page::with_capacity(self.page_capacity);
-            page.add_str(s);
-            self.pages.push_back(page);
+            let capacity = s.len().max(self.page_capacity);
+            let mut evicted = None;
+            while !self.pages.is_empty() && self.allocated() + capacity > self.budget() {
+                evicted = Some(self.pop_front_page());
+            }
+
+            // Reuse the allocation unless a snapshot still reads the evicted page
+            let mut page = evicted
+                .filter(|page| page.capacity == capacity)
+                .and_then(|page| Arc::try_unwrap(page).ok())
+                .unwrap_or_else(|| Page::with_capacity(capacity));
+            page.clear();
+            page.add_str(s, time);
+            self.pages.push_back(Arc::new(page));
+        }
+        self.held += 1;
+
+        if self.collapse != Collapse::Off {
+            let back = self.pages.back_mut().unwrap();
+            Arc::make_mut(back).set_last_number(self.next_number);
+            self.next_number += 1;
         }
+
+        self.apply_retention(time);
+    }
+
+    fn pop_front_page(&mut self) -> Arc<Page> {
+        let page = self.pages.pop_front().unwrap();
+        self.retain_evicted(&page, self.front_skip..page.len());
+        self.global_offset += page.len() - self.front_skip;
+        self.held -= page.len() - self.front_skip;
+        self.front_skip = 0;
+        page
     }
 
+    /// Evicts lines beyond `Retention` limits, returns the number of lines evicted.
+    pub fn apply_retention(&mut self, now: u64) -> usize {
+        let mut evict = 0;
+
+        if let Some(max_lines) = self.retention.max_lines {
+            evict = self.held.saturating_sub(max_lines);
+        }
+
+        if let Some(max_age) = self.retention.max_age {
+            let cutoff = now.saturating_sub(max_age.as_millis() as u64);
+            let mut expired = 0;
+            for (i, page) in self.pages.iter().enumerate() {
+                let skip = if i == 0 { self.front_skip } else { 0 };
+                // Arrival times only grow, so the first fresh line ends the scan
+                let old = page.times[skip..].partition_point(|&t| t < cutoff);
+                expired += old;
+                if skip + old < page.len() {
+                    break;
+                }
+            }
+            evict = evict.max(expired);
+        }
+
+        self.evict_front(evict);
+        evict
+    }
+
+    fn evict_front(&mut self, mut n: usize) {
+        while n > 0 {
+            let front = self.pages.front().unwrap().clone();
+            let remaining = front.len() - self.front_skip;
+            if n >= remaining && self.pages.len() > 1 {
+                self.pop_front_page();
+                n -= remaining;
+            } else {
+                let k = n.min(remaining);
+                self.retain_evicted(&front, self.front_skip..self.front_skip + k);
+                self.front_skip += k;
+                self.global_offset += k;
+                self.held -= k;
+                return;
+            }
+        }
+    }
+
+    /// Copies lines `range` of the front page that match `Retention::retain` to the side store.
+    fn retain_evicted(&mut self, page: &Page, range: Range<usize>) {
+        let (Some(pattern), Some(retained)) = (&self.retention.retain, &mut self.retained) else {
+            return;
+        };
+        // The front page's first line is numbered `global_offset - front_skip`
+        let page_start = self.global_offset - self.front_skip;
+        for local in range {
+            let line = &page[local];
+            if pattern.is_match(line) {
+                let time = page.time_at(local).unwrap_or_default();
+                let number = page.number_at(local).unwrap_or(page_start + local);
+                Arc::make_mut(retained).add_numbered_line(line, time, number);
+            }
+        }
+    }
+
+    /// Index of the oldest line still held.
     pub fn first_index(&self) -> usize {
         self.global_offset
     }
 
+    /// Fold of line `idx` if it stands for several identical lines.
+    pub fn get_repeat(&self, idx: usize) -> Option<Repeat> {
+        let (page, local) = self.locate(idx)?;
+        page.repeat_at(local)
+    }
+
+    /// Lines received for the sorted entries `indices`, counting folded repeats.
+    pub fn count_occurrences(&self, indices: &[usize]) -> usize {
+        let mut total = indices.len();
+        let mut rdx = self.global_offset;
+        let mut rest = indices;
+        for (i, page) in self.pages.iter().enumerate() {
+            let skip = if i == 0 { self.front_skip } else { 0 };
+            let end = rdx + page.len() - skip;
+            if !page.repeats.is_empty() {
+                for &idx in rest.iter().take_while(|&&idx| idx < end) {
+                    if let Some(repeat) =
+                        idx.checked_sub(rdx).and_then(|l| page.repeat_at(l + skip))
+                    {
+                        total += repeat.count - 1;
+                    }
+                }
+            }
+            rest = &rest[rest.partition_point(|&idx| idx < end)..];
+            rdx = end;
+        }
+        total
+    }
+
+    /// Arrival time of the oldest retained line.
+    pub fn first_time(&self) -> Option<u64> {
+        self.get_time(self.global_offset)
+    }
+
+    /// Arrival time of line `idx`, in milliseconds since the unix epoch.
+    pub fn get_time(&self, idx: usize) -> Option<u64> {
+        let (page, local) = self.locate(idx)?;
+        page.time_at(local)
+    }
+
+    /// Index of the first line whose time is at or after `time`, the time
+    /// written in it or else its arrival time, see [`crate::timerange`].
+    pub fn find_time(&self, time: u64, formats: Option<&FormatSet>) -> Option<usize> {
+        let mut idx = self.global_offset - self.front_skip;
+        for page in &self.pages {
+            for (local, line) in page.iter().enumerate() {
+                let line_time = crate::timerange::line_time(line, formats).or(page.time_at(local));
+                if idx + local >= self.global_offset && line_time.is_some_and(|t| t >= time) {
+                    return Some(idx + local);
+                }
+            }
+            idx += page.len();
+        }
+        None
+    }
+
+    /// Text of line `idx`, `None` once evicted or not yet appended.
     pub fn get_line(&self, idx: usize) -> Option<&str> {
+        let (page, local) = self.locate(idx)?;
+        page.get_at(local)
+    }
+
+    /// Page holding line `idx` and the line's index within it.
+    fn locate(&self, idx: usize) -> Option<(&Page, usize)> {
         if idx < self.global_offset {
             return None;
         }
 
         let mut rdx = self.global_offset;
 
-        for page in &self.pages {
-            if rdx + page.len() > idx {
-                return page.get_at(idx - rdx);
+        for (i, page) in self.pages.iter().enumerate() {
+            let skip = if i == 0 { self.front_skip } else { 0 };
+            let len = page.len() - skip;
+            if rdx + len > idx {
+                return Some((page, idx - rdx + skip));
             }
 
-            rdx += page.len();
+            rdx += len;
         }
 
-        return None;
+        None
     }
 
+    /// One past the index of the newest line, counting evicted lines.
     pub fn lines_count(&self) -> usize {
         self.global_offset + self.current_lines_count()
     }
 
+    /// Lines still held, `lines_count() - first_index()`.
     pub fn current_lines_count(&self) -> usize {
-        let mut size = 0;
-
-        for page in &self.pages {
-            size += page.len();
-        }
-
-        size
+        self.held
     }
 
     pub fn find_next<M: Matcher + ?Sized>(
@@ -126,29 +528,98 @@ impl Pages {
         None
     }
 
+    /// Next occurrence after the one at `range` of line `idx`, moving on to the
+    /// first occurrence of the next matching line. Without a `range` the whole
+    /// of line `idx` is skipped.
+    pub fn find_next_occurrence<M: Matcher + ?Sized>(
+        &self,
+        matcher: &M,
+        idx: usize,
+        range: Option<&Range<usize>>,
+    ) -> Option<(usize, Range<usize>)> {
+        if let (Some(range), Some(line)) = (range, self.get_line(idx)) {
+            let next = matcher
+                .match_ranges(line)
+                .into_iter()
+                .find(|r| r.start > range.start);
+            if let Some(next) = next {
+                return Some((idx, next));
+            }
+        }
+        let (idx, first) = self.find_next(matcher, idx)?;
+        let ranges = self.get_line(idx).map(|line| matcher.match_ranges(line));
+        let first = ranges.and_then(|r| r.into_iter().next()).unwrap_or(first);
+        Some((idx, first))
+    }
+
+    /// Previous occurrence before the one at `range` of line `idx`, moving on to
+    /// the last occurrence of the previous matching line.
+    pub fn find_prev_occurrence<M: Matcher + ?Sized>(
+        &self,
+        matcher: &M,
+        idx: usize,
+        range: Option<&Range<usize>>,
+    ) -> Option<(usize, Range<usize>)> {
+        if let (Some(range), Some(line)) = (range, self.get_line(idx)) {
+            let prev = matcher
+                .match_ranges(line)
+                .into_iter()
+                .rfind(|r| r.start < range.start);
+            if let Some(prev) = prev {
+                return Some((idx, prev));
+            }
+        }
+        let (idx, first) = self.find_prev(matcher, idx)?;
+        let ranges = self.get_line(idx).map(|line| matcher.match_ranges(line));
+        let last = ranges.and_then(|r| r.into_iter().last()).unwrap_or(first);
+        Some((idx, last))
+    }
+
     pub fn find_all_matches<M: Matcher + ?Sized>(&self, matcher: &M) -> Vec<usize> {
+        self.find_all_matches_until(matcher, &AtomicBool::new(false))
+            .unwrap_or_default()
+    }
+
+    /// Like `find_all_matches`, but gives up with `None` once `cancel` is set.
+    ///
+    /// The flag is checked before each page, so a search of a large buffer can
+    /// be abandoned without waiting for it to finish.
+    pub fn find_all_matches_until<M: Matcher + ?Sized>(
+        &self,
+        matcher: &M,
+        cancel: &AtomicBool,
+    ) -> Option<Vec<usize>> {
         use rayon::prelude::*;
 
         let mut page_start_indices = Vec::with_capacity(self.pages.len());
-        let mut current_idx = self.global_offset;
+        let mut current_idx = self.global_offset - self.front_skip;
         for page in &self.pages {
             page_start_indices.push(current_idx);
             current_idx += page.len();
         }
 
-        self.pages
+        let matches = self
+            .pages
             .par_iter()
             .zip(page_start_indices.par_iter())
             .flat_map(|(page, &start_idx)| {
                 let mut page_matches = Vec::new();
+                if cancel.load(Ordering::Relaxed) {
+                    return page_matches;
+                }
                 for (i, line) in page.iter().enumerate() {
-                    if matcher.is_match(line).is_some() {
+                    let matched = || matcher.is_match_at(line, page.time_at(i)).is_some();
+                    if start_idx + i >= self.global_offset && matched() {
                         page_matches.push(start_idx + i);
                     }
                 }
                 page_matches
             })
-            .collect()
+            .collect();
+        if cancel.load(Ordering::Relaxed) {
+            return None;
+        }
+        Some(matches)
     }
 
     pub fn iter(&self) -> PagesIter<'_> {
@@ -156,8 +627,102 @@ impl Pages {
     }
 }
 
+impl<S: AsRef<str>> Extend<S> for Pages {
+    fn extend<I: IntoIterator<Item = S>>(&mut self, lines: I) {
+        for line in lines {
+            self.add_line(line.as_ref());
+        }
+    }
+}
+
+/// Cuts `s` on a char boundary so that it fits `max` bytes along with a marker
+/// noting how much was dropped, or without it when not even the marker fits.
+fn truncate_line(s: &str, max: usize) -> String {
+    let marker = |cut: usize| format!("… [{} bytes truncated]", s.len() - cut);
+    // The longest the marker can be, with every byte dropped
+    let marker_len = marker(0).len();
+    if marker_len > max {
+        return s[..s.floor_char_boundary(max)].to_string();
+    }
+    let cut = s.floor_char_boundary(max - marker_len);
+    format!("{}{}", &s[..cut], marker(cut))
+}
+
+/// Read side of the pages, holding the most recently published snapshot.
+///
+/// The lock only guards swapping the `Arc`, readers never block appends for
+/// longer than a pointer clone.
+#[derive(Clone)]
+pub struct SharedPages(Arc<Mutex<Arc<Pages>>>);
+
+impl SharedPages {
+    pub fn snapshot(&self) -> Arc<Pages> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+/// Write side of the pages, owned by whichever thread ingests lines.
+pub struct PagesWriter {
+    pages: Pages,
+    shared: SharedPages,
+    last_publish: Instant,
+    /// Where every appended line is also persisted
+    session: Option<Session>,
+}
+
+impl PagesWriter {
+    pub fn new(pages: Pages) -> (Self, SharedPages) {
+        let shared = SharedPages(Arc::new(Mutex::new(Arc::new(pages.clone()))));
+        let writer = Self {
+            pages,
+            shared: shared.clone(),
+            last_publish: Instant::now(),
+            session: None,
+        };
+        (writer, shared)
+    }
+
+    pub fn with_session(mut self, session: Session) -> Self {
+        self.session = Some(session);
+        self
+    }
+
+    pub fn add_line(&mut self, s: &str) {
+        let time = now_millis();
+        self.pages.add_line_at(s, time);
+        if let Some(session) = &mut self.session {
+            if let Err(err) = session.append(s, time) {
+                log::error!("failed to persist line, stopping the session {}", err);
+                self.session = None;
+            }
+        }
+    }
+
+    /// Evicts lines that aged out while no new output arrived, returns true if any did.
+    pub fn expire(&mut self) -> bool {
+        self.pages.apply_retention(now_millis()) > 0
+    }
+
+    pub fn since_publish(&self) -> Duration {
+        self.last_publish.elapsed()
+    }
+
+    /// Makes everything appended so far visible to readers, and durable if
+    /// there is a session.
+    pub fn publish(&mut self) {
+        if let Some(session) = &mut self.session {
+            if let Err(err) = session.flush() {
+                log::error!("failed to persist lines, stopping the session {}", err);
+                self.session = None;
+            }
+        }
+        *self.shared.0.lock().unwrap() = Arc::new(self.pages.clone());
+        self.last_publish = Instant::now();
+    }
+}
+
 pub struct PagesIter<'a> {
-    pages: std::collections::vec_deque::Iter<'a, Page>,
+    pages: std::collections::vec_deque::Iter<'a, Arc<Page>>,
     front_iter: Option<PageIter<'a>>,
     back_iter: Option<PageIter<'a>>,
     total_len: usize,
@@ -165,9 +730,16 @@ pub struct PagesIter<'a> {
 
 impl<'a> PagesIter<'a> {
     pub(crate) fn new(pages: &'a Pages) -> Self {
+        let mut iter = pages.pages.iter();
+        let mut front_iter = None;
+        if pages.front_skip > 0 {
+            let mut page_iter = iter.next().unwrap().iter();
+            page_iter.fast_skip(pages.front_skip);
+            front_iter = Some(page_iter);
+        }
         Self {
-            pages: pages.pages.iter(),
-            front_iter: None,
+            pages: iter,
+            front_iter,
             back_iter: None,
             total_len: pages.current_lines_count(),
         }
@@ -241,9 +813,8 @@ impl<'a> Iterator for PagesIter<'a> {
             if let Some(page) = self.pages.next() {
                 self.front_iter = Some(page.iter());
             } else {
-                return self.back_iter.as_mut()?.next().map(|line| {
+                return self.back_iter.as_mut()?.next().inspect(|_| {
                     self.total_len = self.total_len.saturating_sub(1);
-                    line
                 });
             }
         }
@@ -272,9 +843,8 @@ impl<'a> DoubleEndedIterator for PagesIter<'a> {
             if let Some(page) = self.pages.next_back() {
                 self.back_iter = Some(page.iter());
             } else {
-                return self.front_iter.as_mut()?.next_back().map(|line| {
+                return self.front_iter.as_mut()?.next_back().inspect(|_| {
                     self.total_len = self.total_len.saturating_sub(1);
-                    line
                 });
             }
         }
@@ -283,10 +853,44 @@ impl<'a> DoubleEndedIterator for PagesIter<'a> {
 
 impl<'a> ExactSizeIterator for PagesIter<'a> {}
 
-#[derive(Default, Clone)]
+#[derive(Default)]
 pub struct Page {
     inner: String,
     indices: Vec<usize>,
+    /// Arrival time of each line, in milliseconds since the unix epoch
+    times: Vec<u64>,
+    /// Line numbers when they differ from the index: original numbers in a
+    /// retained side store, or numbers counting folded repeats
+    numbers: Vec<usize>,
+    /// Folded duplicates, sorted by line
+    repeats: Vec<Repeat>,
+    capacity: usize,
+}
+
+/// Consecutive duplicates folded into line `idx` of a page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Repeat {
+    pub idx: usize,
+    /// Total occurrences, including the first
+    pub count: usize,
+    /// Arrival time of the latest occurrence
+    pub last_time: u64,
+}
+
+impl Clone for Page {
+    // A derived clone would shrink `inner` to its length and make the copy look full
+    fn clone(&self) -> Self {
+        let mut inner = String::with_capacity(self.capacity);
+        inner.push_str(&self.inner);
+        Self {
+            inner,
+            indices: self.indices.clone(),
+            times: self.times.clone(),
+            numbers: self.numbers.clone(),
+            repeats: self.repeats.clone(),
+            capacity: self.capacity,
+        }
+    }
 }
 
 impl Page {
@@ -294,28 +898,37 @@ impl Page {
         Self {
             inner: String::with_capacity(cap),
             indices: Vec::new(),
+            times: Vec::new(),
+            numbers: Vec::new(),
+            repeats: Vec::new(),
+            capacity: cap,
         }
     }
 
-    pub fn add_str(&mut self, s: &str) {
-        for line in s.lines() {
-            self.add_line(line);
-        }
+    /// Adds `s` as one line, even if it is a record spanning several physical lines.
+    pub fn add_str(&mut self, s: &str, time: u64) {
+        self.add_line(s.strip_suffix('\r').unwrap_or(s), time);
     }
 
     /// returns index of line
-    pub fn add_line(&mut self, s: &str) -> usize {
+    pub fn add_line(&mut self, s: &str, time: u64) -> usize {
         self.indices.push(self.inner.len());
+        self.times.push(time);
         self.inner.push_str(s);
         self.len() - 1
     }
 
+    fn fits(&self, s: &str) -> bool {
+        self.inner.len() + s.len() <= self.capacity
+    }
+
+    #[cfg(test)]
     fn add_str_only_if_in_cap(&mut self, s: &str) -> bool {
-        if self.inner.len() + s.len() > self.inner.capacity() {
+        if !self.fits(s) {
             return false;
         }
-        self.add_str(s);
-        return true;
+        self.add_str(s, now_millis());
+        true
     }
 
     #[inline]
@@ -323,15 +936,56 @@ impl Page {
         self.indices.len()
     }
 
+    #[inline]
+    pub const fn is_empty(&self) -> bool {
+        self.indices.is_empty()
+    }
+
     pub fn get_at(&self, idx: usize) -> Option<&str> {
         let start = *self.indices.get(idx)?;
         let end = *self.indices.get(idx + 1).unwrap_or(&self.inner.len());
         Some(&self.inner[start..end])
     }
 
+    pub fn time_at(&self, idx: usize) -> Option<u64> {
+        self.times.get(idx).copied()
+    }
+
+    pub fn number_at(&self, idx: usize) -> Option<usize> {
+        self.numbers.get(idx).copied()
+    }
+
+    fn set_last_number(&mut self, number: usize) {
+        self.numbers.resize(self.len(), number);
+    }
+
+    pub fn repeat_at(&self, idx: usize) -> Option<Repeat> {
+        let pos = self.repeats.binary_search_by_key(&idx, |r| r.idx).ok()?;
+        Some(self.repeats[pos])
+    }
+
+    /// Counts another occurrence of the last line.
+    fn add_repeat(&mut self, time: u64) {
+        let idx = self.len() - 1;
+        match self.repeats.last_mut() {
+            Some(repeat) if repeat.idx == idx => {
+                repeat.count += 1;
+                repeat.last_time = time;
+            }
+            _ => self.repeats.push(Repeat {
+                idx,
+                count: 2,
+                last_time: time,
+            }),
+        }
+    }
+
     pub fn clear(&mut self) {
         self.inner.clear();
         self.indices.clear();
+        self.times.clear();
+        self.numbers.clear();
+        self.repeats.clear();
     }
 
     pub fn iter(&self) -> PageIter<'_> {
@@ -410,10 +1064,10 @@ impl Index<usize> for Page {
 fn test_page_overflow() {
     let mut page = Page::with_capacity(10);
     // "hello" is 5 bytes.
-    assert_eq!(page.add_str_only_if_in_cap("hello"), true);
-    assert_eq!(page.add_str_only_if_in_cap("world"), true);
+    assert!(page.add_str_only_if_in_cap("hello"));
+    assert!(page.add_str_only_if_in_cap("world"));
     // Capacity is 10, used 10. Next addition should fail.
-    assert_eq!(page.add_str_only_if_in_cap("!"), false);
+    assert!(!page.add_str_only_if_in_cap("!"));
 
     assert_eq!(page.len(), 2);
     assert_eq!(&page[0], "hello");
@@ -472,6 +1126,104 @@ fn test_pages_overflow_recycle() {
     assert_eq!(pages.current_lines_count(), 2);
 }
 
+#[test]
+fn test_pages_oversized_line() {
+    // Budget of 40 bytes, lines up to 20 bytes
+    let mut pages = Pages::new(10, 4).with_max_line_size(20);
+    pages.add_line("small");
+    pages.add_line("also-small");
+
+    // Too big for a page: gets a dedicated 18 byte page, no history is lost
+    pages.add_line("an-oversized-line!");
+    assert_eq!(pages.pages.len(), 3);
+    assert_eq!(pages.allocated(), 38);
+    assert_eq!(pages.first_index(), 0);
+    assert_eq!(pages.get_line(2), Some("an-oversized-line!"));
+
+    // The next regular page doesn't fit the budget, the oldest page goes
+    pages.add_line("tail");
+    assert_eq!(pages.first_index(), 1);
+    assert_eq!(pages.get_line(1), Some("also-small"));
+    assert_eq!(pages.get_line(2), Some("an-oversized-line!"));
+    assert_eq!(pages.get_line(3), Some("tail"));
+    assert!(pages.allocated() <= 40);
+}
+
+#[test]
+fn test_pages_truncate_huge_line() {
+    let mut pages = Pages::new(10, 8).with_max_line_size(40);
+    pages.add_line("keep");
+
+    let huge = "x".repeat(1000);
+    pages.add_line(&huge);
+
+    assert_eq!(pages.get_line(0), Some("keep"));
+    let line = pages.get_line(1).unwrap();
+    assert!(line.len() <= 40);
+    assert!(line.starts_with("xxxxxxxxxx"));
+    assert!(line.ends_with(" bytes truncated]"));
+    assert!(pages.allocated() <= 80);
+
+    // A cap shorter than the marker still holds
+    let mut pages = Pages::new(64, 4).with_max_line_size(8);
+    pages.add_line(&"é".repeat(30));
+    assert_eq!(pages.get_line(0), Some("éééé"));
+
+    // Without a cap only lines beyond the whole budget are cut
+    let mut pages = Pages::new(10, 8);
+    pages.add_line(&"y".repeat(60));
+    assert_eq!(pages.get_line(0).map(str::len), Some(60));
+    pages.add_line(&huge);
+    let line = pages.get_line(1).unwrap();
+    assert!(line.len() <= 80 && line.ends_with(" bytes truncated]"));
+    assert_eq!(pages.first_index(), 1);
+}
+
+#[test]
+fn test_collapse_repeats() {
+    let mut pages = Pages::new(100, 4).with_collapse(Collapse::Fuzzy);
+    pages.add_line_at("start", 1);
+    pages.add_line_at("retry 1 failed at 10:00:01", 2);
+    pages.add_line_at("retry 2 failed at 10:00:02", 3);
+    pages.add_line_at("retry 3 failed at 10:00:03", 4);
+    pages.add_line_at("done", 5);
+
+    // Three entries stand for five lines
+    assert_eq!(pages.lines_count(), 3);
+    assert_eq!(pages.get_line(1), Some("retry 1 failed at 10:00:01"));
+    assert_eq!(
+        pages.get_repeat(1),
+        Some(Repeat {
+            idx: 1,
+            count: 3,
+            last_time: 4
+        })
+    );
+    assert_eq!(pages.get_repeat(2), None);
+    assert_eq!(pages.line_number(1), 1);
+    assert_eq!(pages.line_number(2), 4);
+    // Numbers inside a fold go to the fold
+    assert_eq!(pages.index_of_number(3), Some(1));
+    assert_eq!(pages.index_of_number(4), Some(2));
+    assert_eq!(pages.index_of_number(5), None);
+    assert_eq!(pages.count_occurrences(&[1, 2]), 4);
+
+    // Month and weekday names and AM/PM differ like the digits around them
+    assert!(eq_ignoring_timestamps(
+        "Mon Oct 18 11:59:59 AM tick",
+        "Tue Nov 1 12:00:00 PM tick"
+    ));
+    assert!(eq_ignoring_timestamps("Sat jan 1", "sun FEBRUARY 2"));
+    assert!(!eq_ignoring_timestamps("Mon tick", "Jan tick"));
+    assert!(!eq_ignoring_timestamps("Mon tick", "Month tick"));
+    assert!(!eq_ignoring_timestamps("retry 1 failed", "retry 2 passed"));
+
+    let mut pages = Pages::new(100, 4).with_collapse(Collapse::Exact);
+    pages.add_line("retry 1");
+    pages.add_line("retry 2");
+    assert_eq!(pages.lines_count(), 2);
+}
+
 #[test]
 fn test_find_all_matches() {
     let mut pages = Pages::new(100, 5);
@@ -506,3 +1258,149 @@ fn test_find_all_matches() {
     let matches = pages.find_all_matches(&matcher);
     assert_eq!(matches, vec![2]);
 }
+
+#[test]
+fn test_find_occurrences() {
+    use crate::command::SearchPattern;
+
+    let mut pages = Pages::new(100, 5);
+    pages.add_line("error one error two");
+    pages.add_line("fine");
+    pages.add_line("error three");
+
+    let error = SearchPattern::Substring("error".to_string());
+    let next = pages.find_next_occurrence(&error, 0, Some(&(0..5)));
+    assert_eq!(next, Some((0, 10..15)));
+    let next = pages.find_next_occurrence(&error, 0, Some(&(10..15)));
+    assert_eq!(next, Some((2, 0..5)));
+    assert_eq!(pages.find_next_occurrence(&error, 2, Some(&(0..5))), None);
+
+    // Backwards lands on the last occurrence of the previous line
+    let prev = pages.find_prev_occurrence(&error, 2, Some(&(0..5)));
+    assert_eq!(prev, Some((0, 10..15)));
+    let prev = pages.find_prev_occurrence(&error, 0, Some(&(10..15)));
+    assert_eq!(prev, Some((0, 0..5)));
+}
+
+#[test]
+fn test_snapshot_isolated_from_appends() {
+    let (mut writer, shared) = PagesWriter::new(Pages::new(100, 2));
+    writer.add_line("first");
+    writer.publish();
+
+    let snapshot = shared.snapshot();
+    writer.add_line("second");
+    assert_eq!(snapshot.lines_count(), 1);
+    assert_eq!(writer.pages.lines_count(), 2);
+
+    // The copied page keeps its capacity instead of rotating early
+    assert_eq!(writer.pages.pages.len(), 1);
+
+    writer.publish();
+    assert_eq!(shared.snapshot().get_line(1), Some("second"));
+}
+
+#[test]
+fn test_retention_max_lines() {
+    let mut pages = Pages::new(100, 4).with_retention(Retention {
+        max_lines: Some(3),
+        ..Default::default()
+    });
+    for i in 0..5 {
+        pages.add_line(&format!("line {}", i));
+    }
+
+    assert_eq!(pages.first_index(), 2);
+    assert_eq!(pages.lines_count(), 5);
+    assert_eq!(pages.current_lines_count(), 3);
+    assert_eq!(pages.get_line(1), None);
+    assert_eq!(pages.get_line(2), Some("line 2"));
+    assert_eq!(
+        pages.iter().collect::<Vec<_>>(),
+        vec!["line 2", "line 3", "line 4"]
+    );
+
+    struct SubstringMatcher(&'static str);
+    impl Matcher for SubstringMatcher {
+        fn is_match(&self, s: &str) -> Option<std::ops::Range<usize>> {
+            let start = s.find(self.0)?;
+            Some(start..start + self.0.len())
+        }
+    }
+    assert_eq!(
+        pages.find_all_matches(&SubstringMatcher("line")),
+        vec![2, 3, 4]
+    );
+
+    // The count holds as whole pages are evicted, by the limit or the budget
+    let mut pages = Pages::new(16, 3).with_retention(Retention {
+        max_lines: Some(5),
+        ..Default::default()
+    });
+    for i in 0..40 {
+        pages.add_line(&format!("line {}", i));
+        let held = (0..=i).filter(|&idx| pages.get_line(idx).is_some()).count();
+        assert_eq!(pages.current_lines_count(), held);
+    }
+    assert_eq!(pages.lines_count(), 40);
+}
+
+#[test]
+fn test_retention_max_age() {
+    // All lines share one page, so expiry trims it in place
+    let mut pages = Pages::new(100, 8).with_retention(Retention {
+        max_age: Some(Duration::from_secs(10)),
+        ..Default::default()
+    });
+    pages.add_line_at("old 0", 1_000);
+    pages.add_line_at("old 1", 2_000);
+    pages.add_line_at("fresh", 11_500);
+
+    assert_eq!(pages.first_index(), 1);
+    assert_eq!(pages.first_time(), Some(2_000));
+
+    // Nothing new arrives, but time passes
+    assert_eq!(pages.apply_retention(12_500), 1);
+    assert_eq!(pages.first_index(), 2);
+    assert_eq!(pages.get_line(2), Some("fresh"));
+    assert_eq!(pages.current_lines_count(), 1);
+}
+
+#[test]
+fn test_retain_evicted_matches() {
+    let mut pages = Pages::new(10, 2).with_retention(Retention {
+        retain: Some(Regex::new("ERROR|panicked").unwrap()),
+        retain_size: 1024,
+        ..Default::default()
+    });
+    pages.add_line("ERROR: 1");
+    pages.add_line("debug 2");
+    pages.add_line("debug 3");
+    pages.add_line("panicked");
+
+    assert_eq!(pages.first_index(), 2);
+    let retained = pages.retained().unwrap();
+    assert_eq!(retained.lines_count(), 1);
+    assert_eq!(retained.get_line(0), Some("ERROR: 1"));
+    assert_eq!(retained.line_number(0), 0);
+
+    // Partial eviction from a shared page keeps the original numbering
+    let mut pages = Pages::new(100, 2).with_retention(Retention {
+        max_lines: Some(1),
+        retain: Some(Regex::new("keep").unwrap()),
+        retain_size: 1024,
+        ..Default::default()
+    });
+    pages.add_line("drop 0");
+    pages.add_line("keep 1");
+    pages.add_line("drop 2");
+    pages.add_line("last 3");
+
+    let retained = pages.retained().unwrap();
+    assert_eq!(retained.iter().collect::<Vec<_>>(), vec!["keep 1"]);
+    assert_eq!(retained.line_number(0), 1);
+    assert_eq!(retained.index_of_number(1), Some(0));
+    assert_eq!(retained.index_of_number(0), None);
+    assert_eq!(pages.line_number(3), 3);
+    assert_eq!(pages.index_of_number(3), Some(3));
+}
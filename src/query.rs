@@ -0,0 +1,642 @@
+//! Boolean filter expressions.
+//!
+//! ```text
+//! error AND NOT timeout
+//! (db OR cache) AND /slow \d+ms/
+//! "connection reset" -retry
+//! ```
+//!
+//! Terms are bare words, `"quoted phrases"` or `/regexes/`. A regex must close
+//! at the end of a term, so paths like `/api/users` stay plain text. `NOT` and a
+//! leading `-` negate, `AND` binds tighter than `OR`, and terms next to each other are
+//! joined with `AND`. Operators are only recognised in upper case.
+//!
+//! A word like `level=error`, `latency_ms>500` or `user.id!=42` compares a field
+//! of structured lines, see [`crate::fields`]. Values compare as numbers when
+//! both sides are numbers. On lines without fields the word matches as text.
+
+use std::{cell::OnceCell, ops::Range, sync::Arc};
+
+use crate::{
+    command::{merge_ranges, CaseMode, MatchOptions, Matcher, SearchPattern},
+    fields::{parse_fields, Field},
+    format::FormatSet,
+};
+
+/// A parsed filter expression, keeping the text it was parsed from.
+#[derive(Debug, Clone)]
+pub struct Query {
+    source: String,
+    options: MatchOptions,
+    expr: Expr,
+    /// Formats field terms parse lines with
+    formats: Option<Arc<FormatSet>>,
+}
+
+#[derive(Debug, Clone)]
+pub enum Expr {
+    Term(SearchPattern),
+    Field(FieldTerm),
+    Not(Box<Expr>),
+    And(Vec<Expr>),
+    Or(Vec<Expr>),
+}
+
+/// Why a query failed to parse, `position` is a char offset into the query.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub message: String,
+    pub position: usize,
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} at column {}", self.message, self.position + 1)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl Query {
+    pub fn parse(source: &str) -> Result<Self, ParseError> {
+        Self::parse_with(source, MatchOptions::default())
+    }
+
+    /// Parses `source` with `options` applied to every term.
+    pub fn parse_with(source: &str, options: MatchOptions) -> Result<Self, ParseError> {
+        let tokens = tokenize(source, options)?;
+        let mut parser = Parser {
+            tokens,
+            pos: 0,
+            end: source.chars().count(),
+        };
+        let expr = parser.parse_or()?;
+        if let Some((token, position)) = parser.tokens.get(parser.pos) {
+            let message = match token {
+                Token::Close => "unmatched ')'".to_string(),
+                _ => format!("unexpected {}", token),
+            };
+            return Err(ParseError {
+                message,
+                position: *position,
+            });
+        }
+        Ok(Self {
+            source: source.to_string(),
+            options,
+            expr,
+            formats: None,
+        })
+    }
+
+    /// Parses the fields of lines with `formats` too, see [`crate::format`].
+    pub fn with_formats(mut self, formats: Option<Arc<FormatSet>>) -> Self {
+        self.formats = formats;
+        self
+    }
+
+    pub fn options(&self) -> MatchOptions {
+        self.options
+    }
+
+    pub fn expr(&self) -> &Expr {
+        &self.expr
+    }
+}
+
+impl std::fmt::Display for Query {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+/// How a field term compares the field's value to its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompareOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl CompareOp {
+    fn as_str(&self) -> &'static str {
+        match self {
+            CompareOp::Eq => "=",
+            CompareOp::Ne => "!=",
+            CompareOp::Lt => "<",
+            CompareOp::Le => "<=",
+            CompareOp::Gt => ">",
+            CompareOp::Ge => ">=",
+        }
+    }
+}
+
+/// Compares a field, `key OP value`.
+#[derive(Debug, Clone)]
+pub struct FieldTerm {
+    pub key: String,
+    pub op: CompareOp,
+    pub value: String,
+    ignore_case: bool,
+    /// The whole word, matched against lines without fields
+    text: SearchPattern,
+}
+
+impl FieldTerm {
+    /// Parses `key OP value`, `None` if `word` isn't shaped like one.
+    fn parse(
+        word: &str,
+        options: MatchOptions,
+    ) -> Option<Result<Self, crate::command::PatternError>> {
+        let op_start = word.find(['=', '!', '<', '>'])?;
+        let (key, rest) = word.split_at(op_start);
+        let valid_key = key.starts_with(|c: char| c.is_alphabetic() || c == '_')
+            && key
+                .chars()
+                .all(|c| c.is_alphanumeric() || matches!(c, '_' | '.' | '-' | '@'));
+        if !valid_key {
+            return None;
+        }
+        let (op, value) = [
+            ("!=", CompareOp::Ne),
+            ("<=", CompareOp::Le),
+            (">=", CompareOp::Ge),
+            ("=", CompareOp::Eq),
+            ("<", CompareOp::Lt),
+            (">", CompareOp::Gt),
+        ]
+        .into_iter()
+        .find_map(|(token, op)| Some((op, rest.strip_prefix(token)?)))?;
+        if value.is_empty() {
+            return None;
+        }
+        let ignore_case = match options.case {
+            CaseMode::Sensitive => false,
+            CaseMode::Insensitive => true,
+            CaseMode::Smart => !value.chars().any(char::is_uppercase),
+        };
+        Some(SearchPattern::new(word, false, options).map(|text| Self {
+            key: key.to_string(),
+            op,
+            value: value.to_string(),
+            ignore_case,
+            text,
+        }))
+    }
+
+    /// Whether `value` compares true against the term's value.
+    fn compare(&self, value: &str) -> bool {
+        let ordering = match (value.parse::<f64>(), self.value.parse::<f64>()) {
+            (Ok(a), Ok(b)) => a.partial_cmp(&b),
+            _ if self.ignore_case => Some(value.to_lowercase().cmp(&self.value.to_lowercase())),
+            _ => Some(value.cmp(&self.value)),
+        };
+        let Some(ordering) = ordering else {
+            return false;
+        };
+        match self.op {
+            CompareOp::Eq => ordering.is_eq(),
+            CompareOp::Ne => ordering.is_ne(),
+            CompareOp::Lt => ordering.is_lt(),
+            CompareOp::Le => ordering.is_le(),
+            CompareOp::Gt => ordering.is_gt(),
+            CompareOp::Ge => ordering.is_ge(),
+        }
+    }
+
+    /// Whether the field compares true, with the range of its value. A missing
+    /// field only satisfies `!=`.
+    fn eval(&self, line: &Subject) -> (bool, Option<Range<usize>>) {
+        let Some(fields) = line.fields() else {
+            let range = self.text.is_match(line.text);
+            return (range.is_some(), range);
+        };
+        match fields.iter().find(|field| field.key == self.key) {
+            Some(field) if self.compare(&field.value) => (true, field.range.clone()),
+            Some(_) => (false, None),
+            None => (self.op == CompareOp::Ne, None),
+        }
+    }
+}
+
+impl std::fmt::Display for FieldTerm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}{}{}", self.key, self.op.as_str(), self.value)
+    }
+}
+
+/// A line being matched, with its fields parsed on first use.
+struct Subject<'a> {
+    text: &'a str,
+    formats: Option<&'a FormatSet>,
+    fields: OnceCell<Option<Vec<Field>>>,
+}
+
+impl<'a> Subject<'a> {
+    fn new(text: &'a str, formats: Option<&'a FormatSet>) -> Self {
+        Self {
+            text,
+            formats,
+            fields: OnceCell::new(),
+        }
+    }
+
+    fn fields(&self) -> Option<&[Field]> {
+        self.fields
+            .get_or_init(|| parse_fields(self.text, self.formats))
+            .as_deref()
+    }
+}
+
+impl Expr {
+    /// Whether `line` matches, with the range of the first positive term that matched.
+    fn eval(&self, line: &Subject) -> (bool, Option<Range<usize>>) {
+        match self {
+            Expr::Term(pattern) => {
+                let range = pattern.is_match(line.text);
+                (range.is_some(), range)
+            }
+            Expr::Field(term) => term.eval(line),
+            Expr::Not(expr) => (!expr.eval(line).0, None),
+            Expr::And(exprs) => {
+                let mut first = None;
+                for expr in exprs {
+                    let (matched, range) = expr.eval(line);
+                    if !matched {
+                        return (false, None);
+                    }
+                    first = first.or(range);
+                }
+                (true, first)
+            }
+            Expr::Or(exprs) => {
+                let mut matched_any = false;
+                for expr in exprs {
+                    let (matched, range) = expr.eval(line);
+                    if matched && range.is_some() {
+                        return (true, range);
+                    }
+                    matched_any |= matched;
+                }
+                (matched_any, None)
+            }
+        }
+    }
+
+    /// Collects the occurrences of the terms that aren't under a negation.
+    fn positive_ranges(&self, line: &Subject, ranges: &mut Vec<Range<usize>>) {
+        match self {
+            Expr::Term(pattern) => ranges.extend(pattern.match_ranges(line.text)),
+            Expr::Field(term) => match term.eval(line) {
+                (true, Some(range)) if !range.is_empty() => ranges.push(range),
+                _ => {}
+            },
+            Expr::Not(_) => {}
+            Expr::And(exprs) | Expr::Or(exprs) => {
+                for expr in exprs {
+                    expr.positive_ranges(line, ranges);
+                }
+            }
+        }
+    }
+}
+
+impl Matcher for Query {
+    /// Lines matched only through negated terms get an empty highlight.
+    fn is_match(&self, s: &str) -> Option<Range<usize>> {
+        match self.expr.eval(&Subject::new(s, self.formats.as_deref())) {
+            (true, range) => Some(range.unwrap_or(0..0)),
+            (false, _) => None,
+        }
+    }
+
+    /// Occurrences of every positive term, once the whole expression matches.
+    /// Field terms cover the value of their field.
+    fn match_ranges(&self, s: &str) -> Vec<Range<usize>> {
+        let line = Subject::new(s, self.formats.as_deref());
+        if !self.expr.eval(&line).0 {
+            return Vec::new();
+        }
+        let mut ranges = Vec::new();
+        self.expr.positive_ranges(&line, &mut ranges);
+        merge_ranges(ranges)
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Token {
+    Open,
+    Close,
+    And,
+    Or,
+    Not,
+    /// A leading `-` directly attached to what follows
+    Minus,
+    Term(SearchPattern),
+    Field(FieldTerm),
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Token::Open => write!(f, "'('"),
+            Token::Close => write!(f, "')'"),
+            Token::And => write!(f, "AND"),
+            Token::Or => write!(f, "OR"),
+            Token::Not => write!(f, "NOT"),
+            Token::Minus => write!(f, "'-'"),
+            Token::Term(pattern) => write!(f, "'{}'", pattern),
+            Token::Field(term) => write!(f, "'{}'", term),
+        }
+    }
+}
+
+fn tokenize(source: &str, options: MatchOptions) -> Result<Vec<(Token, usize)>, ParseError> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let start = i;
+        let regex = chars[i] == '/' && closing_slash(&chars, i).is_some();
+        match chars[i] {
+            c if c.is_whitespace() => {
+                i += 1;
+                continue;
+            }
+            '(' => {
+                tokens.push((Token::Open, start));
+                i += 1;
+            }
+            ')' => {
+                tokens.push((Token::Close, start));
+                i += 1;
+            }
+            '-' if chars.get(i + 1).is_some_and(|c| !c.is_whitespace()) => {
+                tokens.push((Token::Minus, start));
+                i += 1;
+            }
+            quote @ ('"' | '/') if quote == '"' || regex => {
+                let mut text = String::new();
+                i += 1;
+                loop {
+                    match chars.get(i) {
+                        None => {
+                            let what = if quote == '"' { "phrase" } else { "regex" };
+                            return Err(ParseError {
+                                message: format!(
+                                    "unterminated {}, missing closing {}",
+                                    what, quote
+                                ),
+                                position: start,
+                            });
+                        }
+                        Some('\\') if chars.get(i + 1) == Some(&quote) => {
+                            text.push(quote);
+                            i += 2;
+                        }
+                        Some(&c) if c == quote => {
+                            i += 1;
+                            break;
+                        }
+                        Some(&c) => {
+                            text.push(c);
+                            i += 1;
+                        }
+                    }
+                }
+                let pattern =
+                    SearchPattern::new(&text, quote == '/', options).map_err(|err| ParseError {
+                        message: format!("invalid regex, {}", err.message),
+                        position: start + 1 + err.position.unwrap_or(0),
+                    })?;
+                tokens.push((Token::Term(pattern), start));
+            }
+            _ => {
+                while i < chars.len() && !chars[i].is_whitespace() && !matches!(chars[i], '(' | ')')
+                {
+                    i += 1;
+                }
+                let word: String = chars[start..i].iter().collect();
+                let invalid = |err: crate::command::PatternError| ParseError {
+                    message: format!("invalid term, {}", err.message),
+                    position: start + err.position.unwrap_or(0),
+                };
+                let token = match word.as_str() {
+                    "AND" => Token::And,
+                    "OR" => Token::Or,
+                    "NOT" => Token::Not,
+                    _ => match FieldTerm::parse(&word, options) {
+                        Some(term) => Token::Field(term.map_err(invalid)?),
+                        None => {
+                            Token::Term(SearchPattern::new(&word, false, options).map_err(invalid)?)
+                        }
+                    },
+                };
+                tokens.push((token, start));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+/// Position of the `/` closing a regex opened at `start`, which must be
+/// followed by whitespace, `)` or the end. Otherwise the term is plain text,
+/// like a path.
+fn closing_slash(chars: &[char], start: usize) -> Option<usize> {
+    let mut i = start + 1;
+    while i < chars.len() {
+        match chars[i] {
+            '\\' if chars.get(i + 1) == Some(&'/') => i += 2,
+            '/' => {
+                let boundary = chars
+                    .get(i + 1)
+                    .is_none_or(|&c| c.is_whitespace() || c == ')');
+                return boundary.then_some(i);
+            }
+            _ => i += 1,
+        }
+    }
+    None
+}
+
+/// Recursive descent over `or := and (OR and)*`, `and := unary (AND? unary)*`,
+/// `unary := (NOT | -) unary | ( or ) | term`.
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    pos: usize,
+    /// Length of the query in chars
+    end: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(token, _)| token)
+    }
+
+    /// Position of the next token, or the end of the query
+    fn position(&self) -> usize {
+        match self.tokens.get(self.pos) {
+            Some((_, position)) => *position,
+            None => self.end,
+        }
+    }
+
+    fn parse_or(&mut self) -> Result<Expr, ParseError> {
+        let mut exprs = vec![self.parse_and()?];
+        while let Some(Token::Or) = self.peek() {
+            self.pos += 1;
+            exprs.push(self.parse_and()?);
+        }
+        Ok(if exprs.len() == 1 {
+            exprs.pop().unwrap()
+        } else {
+            Expr::Or(exprs)
+        })
+    }
+
+    fn parse_and(&mut self) -> Result<Expr, ParseError> {
+        let mut exprs = vec![self.parse_unary()?];
+        loop {
+            match self.peek() {
+                Some(Token::And) => {
+                    self.pos += 1;
+                    exprs.push(self.parse_unary()?);
+                }
+                Some(
+                    Token::Not | Token::Minus | Token::Open | Token::Term(_) | Token::Field(_),
+                ) => {
+                    exprs.push(self.parse_unary()?);
+                }
+                _ => break,
+            }
+        }
+        Ok(if exprs.len() == 1 {
+            exprs.pop().unwrap()
+        } else {
+            Expr::And(exprs)
+        })
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, ParseError> {
+        let position = self.position();
+        let Some((token, _)) = self.tokens.get(self.pos).cloned() else {
+            return Err(ParseError {
+                message: "expected a term".to_string(),
+                position,
+            });
+        };
+        self.pos += 1;
+        match token {
+            Token::Not | Token::Minus => Ok(Expr::Not(Box::new(self.parse_unary()?))),
+            Token::Open => {
+                let expr = self.parse_or()?;
+                match self.peek() {
+                    Some(Token::Close) => {
+                        self.pos += 1;
+                        Ok(expr)
+                    }
+                    _ => Err(ParseError {
+                        message: "missing ')' for this '('".to_string(),
+                        position,
+                    }),
+                }
+            }
+            Token::Term(pattern) => Ok(Expr::Term(pattern)),
+            Token::Field(term) => Ok(Expr::Field(term)),
+            token => Err(ParseError {
+                message: format!("expected a term, found {}", token),
+                position,
+            }),
+        }
+    }
+}
+
+#[test]
+fn test_query_matching() {
+    let query = Query::parse("error AND NOT timeout").unwrap();
+    assert_eq!(query.is_match("an error occurred"), Some(3..8));
+    assert_eq!(query.is_match("error: timeout"), None);
+
+    let query = Query::parse(r"(db OR cache) AND /slow \d+ms/").unwrap();
+    assert_eq!(query.is_match("cache slow 20ms"), Some(0..5));
+    assert_eq!(query.is_match("db fast 20ms"), None);
+
+    let query = Query::parse(r#""connection reset" -retry"#).unwrap();
+    assert_eq!(query.is_match("x connection reset"), Some(2..18));
+    assert_eq!(query.is_match("connection reset, retry"), None);
+
+    // Only negative terms still match, with nothing to highlight
+    let query = Query::parse("-debug").unwrap();
+    assert_eq!(query.is_match("info"), Some(0..0));
+    assert!(query.match_ranges("info").is_empty());
+
+    let query = Query::parse("db OR cache -timeout").unwrap();
+    assert_eq!(query.match_ranges("cache db cache"), [0..5, 6..8, 9..14]);
+    assert_eq!(query.match_ranges("db db timeout"), [0..2, 3..5]);
+    assert!(query.match_ranges("cache timeout").is_empty());
+
+    // Slashes that don't close a regex are part of the text
+    let query = Query::parse("GET /api").unwrap();
+    assert_eq!(query.is_match("GET /api/v1"), Some(0..3));
+    assert_eq!(query.is_match("GET /v1"), None);
+    let query = Query::parse("/api/users").unwrap();
+    assert_eq!(query.is_match("GET /api/users"), Some(4..14));
+    assert_eq!(query.is_match("GET /v1/users api"), None);
+    let query = Query::parse("http://host/path").unwrap();
+    assert_eq!(query.is_match("fetch http://host/path ok"), Some(6..22));
+    let query = Query::parse("(/ab+c/)").unwrap();
+    assert_eq!(query.is_match("xabbc"), Some(1..5));
+}
+
+#[test]
+fn test_query_fields() {
+    let line = r#"{"level":"error","latency_ms":512,"user":{"id":42},"msg":"slow db"}"#;
+    let matches = |q: &str| Query::parse(q).unwrap().is_match(line).is_some();
+    assert!(matches("level=error"));
+    assert!(!matches("level=ERROR") && !matches("level=warn"));
+    let options = MatchOptions::from_flags("i");
+    assert!(Query::parse_with("level=ERROR", options)
+        .unwrap()
+        .is_match(line)
+        .is_some());
+    assert!(matches("latency_ms>500 latency_ms<=512.0"));
+    assert!(!matches("latency_ms>=1000"));
+    assert!(matches("user.id=42 AND -user.id!=42"));
+    assert!(matches("missing!=x") && !matches("missing=x"));
+    assert!(matches("slow"));
+
+    // Only the value of a field is highlighted
+    let query = Query::parse("level=error db").unwrap();
+    let ranges = query.match_ranges(line);
+    let highlighted: Vec<_> = ranges.iter().map(|r| &line[r.clone()]).collect();
+    assert_eq!(highlighted, ["error", "db"]);
+
+    // Lines without fields match the word as text
+    let query = Query::parse("level=error").unwrap();
+    assert_eq!(query.is_match("at 12:00 level=error seen"), Some(9..20));
+    assert_eq!(query.is_match("at 12:00 level=warn seen"), None);
+
+    // logfmt pairs are fields too
+    assert_eq!(query.is_match("ts=1 level=error"), Some(11..16));
+    assert_eq!(query.is_match(r#"level="error" msg=x"#), Some(7..12));
+}
+
+#[test]
+fn test_query_errors() {
+    let err = |s: &str| Query::parse(s).unwrap_err().to_string();
+    assert_eq!(err("(db OR cache"), "missing ')' for this '(' at column 1");
+    assert_eq!(err("db)"), "unmatched ')' at column 3");
+    assert_eq!(err("db AND"), "expected a term at column 7");
+    assert_eq!(err("OR db"), "expected a term, found OR at column 1");
+    assert_eq!(
+        err(r#"say "hi"#),
+        "unterminated phrase, missing closing \" at column 5"
+    );
+    assert_eq!(
+        err("db AND /slow (/"),
+        "invalid regex, unclosed group at column 14"
+    );
+}
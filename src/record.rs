@@ -0,0 +1,194 @@
+//! Joining physical lines into multi-line records, such as stack traces.
+//!
+//! A record is stored as a single line of the pages with its physical lines
+//! joined by `\n`, so filtering, searching and folding treat it as one unit.
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use regex::Regex;
+
+use crate::source::SourceId;
+
+/// Physical lines a record may hold before it is cut, so a start pattern that
+/// never matches can't hold back output forever.
+const MAX_RECORD_LINES: usize = 1000;
+
+/// How the start of a record is recognised, every other line continues the
+/// record above it.
+#[derive(Debug, Clone)]
+pub enum RecordStart {
+    /// Lines matching the regex start a record
+    Regex(Regex),
+    /// Indented lines and the known shapes of Java, Python and Rust stack
+    /// traces continue a record, anything else starts one
+    Heuristic,
+}
+
+impl RecordStart {
+    /// Whether `line` continues `record`, the lines joined so far.
+    pub fn is_continuation(&self, record: &str, line: &str) -> bool {
+        match self {
+            RecordStart::Regex(regex) => !regex.is_match(line),
+            RecordStart::Heuristic => {
+                if line.starts_with([' ', '\t']) || line.starts_with("Caused by:") {
+                    return true;
+                }
+                let first = record.split('\n').next().unwrap_or_default();
+                let last = record.rsplit('\n').next().unwrap_or_default();
+                // The exception closing a Python traceback isn't indented
+                if first.starts_with("Traceback (most recent call last):") {
+                    return last.starts_with([' ', '\t']);
+                }
+                // Rust prints the panic message and the backtrace note below the header
+                if first.contains(" panicked at ") {
+                    return (record.len() == first.len() && first.ends_with(':'))
+                        || line.starts_with("stack backtrace:")
+                        || line.starts_with("note: ");
+                }
+                false
+            }
+        }
+    }
+}
+
+struct Pending {
+    text: String,
+    lines: usize,
+    updated: Instant,
+}
+
+/// Builds records out of the lines of each source.
+///
+/// A record is complete once the next one starts, its source closes, or it
+/// got no line for a while, see [`RecordJoiner::flush_idle`].
+pub struct RecordJoiner {
+    start: RecordStart,
+    pending: HashMap<SourceId, Pending>,
+}
+
+impl RecordJoiner {
+    pub fn new(start: RecordStart) -> Self {
+        Self {
+            start,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Adds a line of `source`, returning the record it completes, if any.
+    pub fn push(&mut self, source: SourceId, line: &str) -> Option<String> {
+        if let Some(pending) = self.pending.get_mut(&source) {
+            if pending.lines < MAX_RECORD_LINES && self.start.is_continuation(&pending.text, line) {
+                pending.text.push('\n');
+                pending.text.push_str(line);
+                pending.lines += 1;
+                pending.updated = Instant::now();
+                return None;
+            }
+        }
+        let pending = Pending {
+            text: line.to_string(),
+            lines: 1,
+            updated: Instant::now(),
+        };
+        self.pending
+            .insert(source, pending)
+            .map(|pending| pending.text)
+    }
+
+    /// Completes the pending record of `source`.
+    pub fn flush(&mut self, source: SourceId) -> Option<String> {
+        self.pending.remove(&source).map(|pending| pending.text)
+    }
+
+    /// Completes the records that got no line for `idle`, oldest first.
+    pub fn flush_idle(&mut self, idle: Duration) -> Vec<(SourceId, String)> {
+        let mut idle_sources: Vec<(Instant, SourceId)> = self
+            .pending
+            .iter()
+            .filter(|(_, pending)| pending.updated.elapsed() >= idle)
+            .map(|(&source, pending)| (pending.updated, source))
+            .collect();
+        idle_sources.sort_unstable();
+        idle_sources
+            .into_iter()
+            .filter_map(|(_, source)| Some((source, self.flush(source)?)))
+            .collect()
+    }
+
+    /// Completes every pending record, in source order.
+    pub fn flush_all(&mut self) -> Vec<(SourceId, String)> {
+        let mut sources: Vec<SourceId> = self.pending.keys().copied().collect();
+        sources.sort_unstable();
+        sources
+            .into_iter()
+            .filter_map(|source| Some((source, self.flush(source)?)))
+            .collect()
+    }
+
+    pub fn has_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+}
+
+#[test]
+fn test_record_joiner_heuristics() {
+    let mut joiner = RecordJoiner::new(RecordStart::Heuristic);
+    let mut records = Vec::new();
+    let lines = [
+        "INFO starting",
+        "Exception in thread \"main\" java.lang.IllegalStateException: boom",
+        "\tat App.main(App.java:3)",
+        "Caused by: java.io.IOException: disk",
+        "\t... 1 more",
+        "Traceback (most recent call last):",
+        "  File \"app.py\", line 1, in <module>",
+        "ValueError: bad",
+        "thread 'main' panicked at src/main.rs:2:5:",
+        "oops",
+        "note: run with `RUST_BACKTRACE=1` environment variable to display a backtrace",
+        "INFO done",
+    ];
+    for line in lines {
+        records.extend(joiner.push(0, line));
+    }
+    records.extend(joiner.flush(0));
+
+    let heads: Vec<_> = records
+        .iter()
+        .map(|r| (r.split('\n').next().unwrap(), r.lines().count()))
+        .collect();
+    assert_eq!(
+        heads,
+        [
+            ("INFO starting", 1),
+            (lines[1], 4),
+            (lines[5], 3),
+            (lines[8], 3),
+            ("INFO done", 1),
+        ]
+    );
+}
+
+#[test]
+fn test_record_joiner_regex_per_source() {
+    let start = RecordStart::Regex(Regex::new(r"^\d{4}-").unwrap());
+    let mut joiner = RecordJoiner::new(start);
+    assert_eq!(joiner.push(0, "2024-01-01 a"), None);
+    assert_eq!(joiner.push(1, "2024-01-01 b"), None);
+    assert_eq!(joiner.push(0, "more a"), None);
+    assert_eq!(
+        joiner.push(0, "2024-01-02 c").as_deref(),
+        Some("2024-01-01 a\nmore a")
+    );
+    assert_eq!(
+        joiner.flush_all(),
+        [
+            (0, "2024-01-02 c".to_string()),
+            (1, "2024-01-01 b".to_string())
+        ]
+    );
+    assert!(!joiner.has_pending());
+}
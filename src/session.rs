@@ -0,0 +1,412 @@
+//! Persists received lines and view state to a session directory.
+//!
+//! Layout of a session directory:
+//!
+//! - `segment-NNNNNN.log` holds one record per received line, `<time ms>\t<text>\n`,
+//!   only ever appended to. Each run starts a new segment, so a record torn by a
+//!   crash is always the last one of its file and is skipped on load. Lines
+//!   joined into multi-line records store each continuation as `+\t<text>\n`.
+//! - `state` holds the command, filter layers, search and highlight rules, one
+//!   `key\tvalue` per line, with backslashes, tabs and line breaks in values
+//!   escaped. It is rewritten through a temporary file and a rename.
+
+use std::{
+    collections::VecDeque,
+    fs::{self, File},
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use regex::Regex;
+
+use crate::{
+    command::{Command, MatchOptions, SearchPattern},
+    filter::FilterLayer,
+    highlight::HighlightRule,
+    pages::Pages,
+    query::Query,
+    timerange::TimeRange,
+};
+
+/// Size at which a new segment is started.
+const SEGMENT_SIZE: u64 = 4 * 1024 * 1024;
+
+const STATE_FILE: &str = "state";
+
+/// Append side of a session, see the module docs for the layout.
+pub struct Session {
+    dir: PathBuf,
+    /// Ids and sizes of the segments on disk, oldest first, the last one open
+    segments: VecDeque<(u64, u64)>,
+    writer: BufWriter<File>,
+    max_size: u64,
+}
+
+impl Session {
+    /// Starts a new session in `dir`, which must not hold one already.
+    pub fn create(dir: &Path, max_size: u64) -> anyhow::Result<Self> {
+        fs::create_dir_all(dir).with_context(|| format!("can't create {}", dir.display()))?;
+        if !segment_ids(dir)?.is_empty() {
+            anyhow::bail!(
+                "{} already holds a session, use --resume to continue it",
+                dir.display()
+            );
+        }
+        Self::open(dir, VecDeque::new(), max_size)
+    }
+
+    /// Reopens the session in `dir`, replaying its lines into `pages`.
+    ///
+    /// Lines are added with their original arrival times, so retention limits
+    /// apply as if they had never stopped.
+    pub fn resume(dir: &Path, max_size: u64, pages: &mut Pages) -> anyhow::Result<Self> {
+        let ids = segment_ids(dir)?;
+        if ids.is_empty() {
+            anyhow::bail!("{} doesn't hold a session", dir.display());
+        }
+
+        let mut segments = VecDeque::with_capacity(ids.len() + 1);
+        for id in ids {
+            let path = segment_path(dir, id);
+            let file =
+                File::open(&path).with_context(|| format!("can't open {}", path.display()))?;
+            let mut reader = BufReader::new(file);
+            let mut record = Vec::new();
+            let mut size = 0;
+            // A line is only added once its continuations have been read
+            let mut line: Option<(String, u64)> = None;
+            loop {
+                record.clear();
+                let read = reader.read_until(b'\n', &mut record)?;
+                if read == 0 || record.last() != Some(&b'\n') {
+                    break;
+                }
+                size += read as u64;
+                record.pop();
+                let record = String::from_utf8_lossy(&record);
+                let Some((time, text)) = record.split_once('\t') else {
+                    continue;
+                };
+                if time == "+" {
+                    if let Some((line, _)) = &mut line {
+                        line.push('\n');
+                        line.push_str(text);
+                    }
+                } else if let Ok(time) = time.parse() {
+                    if let Some((line, time)) = line.replace((text.to_string(), time)) {
+                        pages.add_line_at(&line, time);
+                    }
+                }
+            }
+            if let Some((line, time)) = line {
+                pages.add_line_at(&line, time);
+            }
+            segments.push_back((id, size));
+        }
+
+        Self::open(dir, segments, max_size)
+    }
+
+    fn open(dir: &Path, mut segments: VecDeque<(u64, u64)>, max_size: u64) -> anyhow::Result<Self> {
+        let id = segments.back().map_or(1, |&(id, _)| id + 1);
+        let path = segment_path(dir, id);
+        let file =
+            File::create(&path).with_context(|| format!("can't create {}", path.display()))?;
+        segments.push_back((id, 0));
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            segments,
+            writer: BufWriter::new(file),
+            max_size,
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn append(&mut self, line: &str, time: u64) -> io::Result<()> {
+        let (_, size) = self.segments.back_mut().unwrap();
+        if *size >= SEGMENT_SIZE {
+            self.rotate()?;
+        }
+
+        let mut record = String::with_capacity(line.len() + 16);
+        for (i, text) in line.split('\n').enumerate() {
+            if i == 0 {
+                record.push_str(&format!("{}\t{}\n", time, text));
+            } else {
+                record.push_str(&format!("+\t{}\n", text));
+            }
+        }
+        self.writer.write_all(record.as_bytes())?;
+        self.segments.back_mut().unwrap().1 += record.len() as u64;
+        Ok(())
+    }
+
+    /// Writes buffered records to the segment file.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    /// Starts the next segment and deletes the oldest ones past `max_size`.
+    fn rotate(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        let id = self.segments.back().unwrap().0 + 1;
+        self.writer = BufWriter::new(File::create(segment_path(&self.dir, id))?);
+        self.segments.push_back((id, 0));
+
+        while self.segments.len() > 1
+            && self.segments.iter().map(|&(_, size)| size).sum::<u64>() > self.max_size
+        {
+            let (id, _) = self.segments.pop_front().unwrap();
+            fs::remove_file(segment_path(&self.dir, id))?;
+        }
+        Ok(())
+    }
+}
+
+fn segment_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("segment-{:06}.log", id))
+}
+
+/// Ids of the segments in `dir`, oldest first.
+fn segment_ids(dir: &Path) -> io::Result<Vec<u64>> {
+    let mut ids = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        let id = name
+            .to_str()
+            .and_then(|name| name.strip_prefix("segment-"))
+            .and_then(|name| name.strip_suffix(".log"))
+            .and_then(|id| id.parse::<u64>().ok());
+        ids.extend(id);
+    }
+    ids.sort_unstable();
+    Ok(ids)
+}
+
+/// What is restored besides the lines when a session is resumed.
+#[derive(Debug, Default, Clone)]
+pub struct SessionState {
+    /// Command of the child process, restarted on request
+    pub command: Vec<String>,
+    pub filters: Vec<FilterLayer>,
+    pub search: Option<Command>,
+    pub highlights: Vec<HighlightRule>,
+}
+
+impl SessionState {
+    pub fn load(dir: &Path) -> io::Result<Self> {
+        let mut state = Self::default();
+        let text = match fs::read_to_string(dir.join(STATE_FILE)) {
+            Ok(text) => text,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(state),
+            Err(err) => return Err(err),
+        };
+
+        for line in text.lines() {
+            let Some((key, value)) = line.split_once('\t') else {
+                continue;
+            };
+            let value = &unescape_value(value);
+            match key {
+                "command" => state.command.push(value.to_string()),
+                "filter" => state.filters.extend(decode_layer(value)),
+                "search" => state.search = decode_command(value),
+                "highlight" => state.highlights.extend(value.parse().ok()),
+                _ => log::warn!("unknown session state key {}", key),
+            }
+        }
+        Ok(state)
+    }
+
+    pub fn save(&self, dir: &Path) -> io::Result<()> {
+        let mut text = String::new();
+        let mut push = |key: &str, value: String| {
+            text.push_str(&format!("{}\t{}\n", key, escape_value(&value)));
+        };
+        for arg in &self.command {
+            push("command", arg.clone());
+        }
+        for layer in &self.filters {
+            let enabled = if layer.enabled { "on" } else { "off" };
+            let command = encode_command(&layer.command);
+            push("filter", format!("{}\t{}", enabled, command));
+        }
+        if let Some(search) = &self.search {
+            push("search", encode_command(search));
+        }
+        for rule in &self.highlights {
+            push("highlight", rule.to_string());
+        }
+
+        let tmp = dir.join(format!("{}.tmp", STATE_FILE));
+        fs::write(&tmp, text)?;
+        fs::rename(tmp, dir.join(STATE_FILE))
+    }
+}
+
+/// Keeps a state value on one line, with its tabs told apart from the one after the key.
+fn escape_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn unescape_value(value: &str) -> String {
+    let mut text = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            text.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => text.push('\n'),
+            Some('r') => text.push('\r'),
+            Some('t') => text.push('\t'),
+            Some('\\') => text.push('\\'),
+            // Left as written, like the regexes of state files saved before escaping
+            Some(c) => {
+                text.push('\\');
+                text.push(c);
+            }
+            None => text.push('\\'),
+        }
+    }
+    text
+}
+
+/// Patterns are stored compiled, with their options inlined, except for
+/// queries which keep their source and options. Time ranges keep their bounds,
+/// so `since 10m ago` means the same after a restart.
+fn encode_command(cmd: &Command) -> String {
+    match cmd {
+        Command::Ignore(SearchPattern::Regex(regex)) => format!("ignore-regex\t{}", regex),
+        Command::Ignore(SearchPattern::Substring(s)) => format!("ignore\t{}", s),
+        Command::SearchFor(SearchPattern::Regex(regex)) => format!("regex\t{}", regex),
+        Command::SearchFor(SearchPattern::Substring(s)) => format!("search\t{}", s),
+        Command::Filter(query) => format!("query/{}\t{}", query.options().flags(), query),
+        Command::TimeRange(range) => format!(
+            "time/{}/{}\t{}",
+            bound(range.since),
+            bound(range.until),
+            range
+        ),
+    }
+}
+
+fn bound(time: Option<u64>) -> String {
+    time.map_or_else(String::new, |time| time.to_string())
+}
+
+fn decode_layer(value: &str) -> Option<FilterLayer> {
+    let (enabled, command) = value.split_once('\t')?;
+    Some(FilterLayer {
+        command: decode_command(command)?,
+        enabled: enabled == "on",
+    })
+}
+
+fn decode_command(value: &str) -> Option<Command> {
+    let (kind, text) = value.split_once('\t')?;
+    let regex = || Regex::new(text).ok().map(SearchPattern::Regex);
+    let substring = || SearchPattern::Substring(text.to_string());
+    match kind {
+        "ignore-regex" => Some(Command::Ignore(regex()?)),
+        "ignore" => Some(Command::Ignore(substring())),
+        "regex" => Some(Command::SearchFor(regex()?)),
+        "search" => Some(Command::SearchFor(substring())),
+        _ if kind.starts_with("time/") => {
+            let (since, until) = kind.strip_prefix("time/")?.split_once('/')?;
+            let bound = |b: &str| b.parse().ok();
+            let range = TimeRange::from_bounds(bound(since), bound(until), text);
+            Some(Command::TimeRange(range))
+        }
+        _ => {
+            let options = MatchOptions::from_flags(kind.strip_prefix("query/")?);
+            Query::parse_with(text, options).ok().map(Command::Filter)
+        }
+    }
+}
+
+#[test]
+fn test_session_resume() {
+    let dir = std::env::temp_dir().join(format!("filter-session-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+
+    let mut session = Session::create(&dir, u64::MAX).unwrap();
+    session.append("first", 1000).unwrap();
+    session.append("second\twith tab", 2000).unwrap();
+    session.append("trace\n  at one\n  at two", 2500).unwrap();
+    session.flush().unwrap();
+    drop(session);
+    // A record torn by a crash is skipped
+    fs::OpenOptions::new()
+        .append(true)
+        .open(segment_path(&dir, 1))
+        .unwrap()
+        .write_all(b"3000\tthi")
+        .unwrap();
+
+    let state = SessionState {
+        command: vec![
+            "echo".to_string(),
+            "a b".to_string(),
+            "two\nlines\tand \\n".to_string(),
+        ],
+        filters: vec![
+            FilterLayer::new(decode_command("query/s\tapi -healthz").unwrap()),
+            FilterLayer {
+                command: decode_command("ignore\tdebug").unwrap(),
+                enabled: false,
+            },
+            FilterLayer::new(decode_command("time/1000/\tsince 10m ago").unwrap()),
+        ],
+        search: decode_command("regex\tsec.nd"),
+        highlights: vec!["/(?i)err/=red".parse().unwrap()],
+    };
+    state.save(&dir).unwrap();
+
+    let mut pages = Pages::default();
+    let mut session = Session::resume(&dir, u64::MAX, &mut pages).unwrap();
+    assert_eq!(
+        pages.iter().collect::<Vec<_>>(),
+        ["first", "second\twith tab", "trace\n  at one\n  at two"]
+    );
+    assert_eq!(pages.get_time(1), Some(2000));
+
+    // Appends go to a new segment
+    session.append("third", 3000).unwrap();
+    session.flush().unwrap();
+    assert_eq!(segment_ids(&dir).unwrap(), [1, 2]);
+
+    let loaded = SessionState::load(&dir).unwrap();
+    assert_eq!(loaded.command, state.command);
+    assert_eq!(loaded.filters.len(), 3);
+    assert_eq!(loaded.filters[0].to_string(), "api -healthz");
+    assert!(matches!(
+        &loaded.filters[0].command,
+        Command::Filter(query) if query.options().case == crate::command::CaseMode::Smart
+    ));
+    assert!(loaded.filters[1].is_exclude() && !loaded.filters[1].enabled);
+    assert!(matches!(
+        &loaded.filters[2].command,
+        Command::TimeRange(range) if range.since == Some(1000) && range.until.is_none()
+    ));
+    assert_eq!(loaded.filters[2].to_string(), "since 10m ago");
+    assert_eq!(loaded.search.unwrap().to_string(), "sec.nd");
+    assert_eq!(loaded.highlights[0].to_string(), "/(?i)err/=Red");
+
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,379 @@
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, Read},
+    net::TcpListener,
+    path::{Path, PathBuf},
+    process::ExitStatus,
+    sync::mpsc::Sender,
+    time::Duration,
+};
+
+use anyhow::Context;
+
+use crate::sync_child::{LineSender, BATCH_LINES};
+
+/// How often a followed file is checked for new data once its end is reached.
+const FOLLOW_INTERVAL: Duration = Duration::from_millis(250);
+/// Longest wait before accepting again after failing to, e.g. out of file descriptors.
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(5);
+
+/// Position of a source in the list it was started from, tagging its lines and events.
+pub type SourceId = usize;
+
+/// What travels from the sources to the ingest thread.
+#[derive(Debug)]
+pub enum SourceMessage {
+    /// Lines read by `source`, in order
+    Lines {
+        source: SourceId,
+        lines: Vec<String>,
+    },
+    Event(SourceEvent),
+}
+
+/// Lifecycle change of a source.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceEvent {
+    pub source: SourceId,
+    pub kind: SourceEventKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SourceEventKind {
+    /// The source is running and may send lines from now on
+    Started,
+    /// The source has no more lines, with the exit status if it was a process
+    Exited(Option<ExitStatus>),
+    /// Reading failed, the source may or may not keep going
+    Error(String),
+}
+
+/// Anything that produces lines for the pages.
+///
+/// A source does its reading on its own threads and sends through the
+/// `LineSender` it is given. It should send `Exited` once it has no more lines,
+/// and drop every clone of the sender so the ingest thread can tell when all
+/// sources are done.
+pub trait LineSource: Send {
+    /// Label used in the title and in lifecycle messages
+    fn name(&self) -> String;
+
+    fn spawn(self: Box<Self>, sender: LineSender) -> anyhow::Result<SourceHandle>;
+}
+
+/// Keeps a running source, stopping it when dropped.
+#[derive(Default)]
+pub struct SourceHandle {
+    input: Option<Sender<u8>>,
+    stop: Option<Box<dyn FnOnce() + Send>>,
+}
+
+impl SourceHandle {
+    /// Keystrokes sent to `input` are forwarded to the source, e.g. a child's stdin
+    pub fn with_input(mut self, input: Sender<u8>) -> Self {
+        self.input = Some(input);
+        self
+    }
+
+    pub fn on_stop(mut self, stop: impl FnOnce() + Send + 'static) -> Self {
+        self.stop = Some(Box::new(stop));
+        self
+    }
+
+    pub fn input(&self) -> Option<&Sender<u8>> {
+        self.input.as_ref()
+    }
+}
+
+impl Drop for SourceHandle {
+    fn drop(&mut self) {
+        if let Some(stop) = self.stop.take() {
+            stop();
+        }
+    }
+}
+
+/// Spawns every source with its own tagged sender, reporting `Started` for each.
+pub fn spawn_sources(
+    sources: Vec<Box<dyn LineSource>>,
+    sender: &LineSender,
+) -> anyhow::Result<Vec<SourceHandle>> {
+    let mut handles = Vec::with_capacity(sources.len());
+    for (id, source) in sources.into_iter().enumerate() {
+        let sender = sender.for_source(id);
+        sender.event(SourceEventKind::Started);
+        handles.push(source.spawn(sender)?);
+    }
+    Ok(handles)
+}
+
+/// Text of a line read with its `\n`, invalid UTF-8 replaced so one bad byte
+/// doesn't end the stream.
+fn decode_line(mut buf: Vec<u8>) -> String {
+    if buf.last() == Some(&b'\n') {
+        buf.pop();
+    }
+    match String::from_utf8(buf) {
+        Ok(line) => line,
+        Err(err) => String::from_utf8_lossy(err.as_bytes()).into_owned(),
+    }
+}
+
+/// Sends lines of `reader` in batches until end of input.
+///
+/// Returns false if the receiving side went away before the end.
+pub(crate) fn read_lines<T: Read>(reader: T, sender: &LineSender) -> io::Result<bool> {
+    let mut reader = BufReader::new(reader);
+    let mut batch = Vec::with_capacity(BATCH_LINES);
+    loop {
+        let mut buf = Vec::new();
+        if reader.read_until(b'\n', &mut buf)? == 0 {
+            break;
+        }
+        batch.push(decode_line(buf));
+
+        // Flush when full, or when the next read would block so slow output isn't held back
+        let flush = batch.len() >= BATCH_LINES || reader.buffer().is_empty();
+        if flush && !sender.send(std::mem::take(&mut batch)) {
+            return Ok(false);
+        }
+    }
+
+    Ok(batch.is_empty() || sender.send(batch))
+}
+
+/// Reads a stream to its end on a thread, then reports `Exited` or the read error.
+fn spawn_reader<T: Read + Send + 'static>(reader: T, sender: LineSender) {
+    std::thread::spawn(move || match read_lines(reader, &sender) {
+        Ok(true) => sender.event(SourceEventKind::Exited(None)),
+        Ok(false) => {}
+        Err(err) => sender.event(SourceEventKind::Error(err.to_string())),
+    });
+}
+
+/// Lines piped into this process.
+pub struct StdinSource;
+
+impl LineSource for StdinSource {
+    fn name(&self) -> String {
+        "stdin".to_string()
+    }
+
+    fn spawn(self: Box<Self>, sender: LineSender) -> anyhow::Result<SourceHandle> {
+        spawn_reader(io::stdin(), sender);
+        Ok(SourceHandle::default())
+    }
+}
+
+/// A file read from the start and then followed as it grows, like `tail -f`.
+///
+/// If the file shrinks, it is assumed to be truncated or rotated and is read
+/// again from the start.
+pub struct FileSource {
+    pub path: PathBuf,
+}
+
+impl LineSource for FileSource {
+    fn name(&self) -> String {
+        self.path.display().to_string()
+    }
+
+    fn spawn(self: Box<Self>, sender: LineSender) -> anyhow::Result<SourceHandle> {
+        let file = File::open(&self.path)
+            .with_context(|| format!("can't open {}", self.path.display()))?;
+        std::thread::spawn(move || {
+            if let Err(err) = follow_file(file, &self.path, &sender) {
+                sender.event(SourceEventKind::Error(err.to_string()));
+            }
+        });
+        Ok(SourceHandle::default())
+    }
+}
+
+fn follow_file(file: File, path: &Path, sender: &LineSender) -> io::Result<()> {
+    let mut reader = BufReader::new(file);
+    let mut pos = 0;
+    let mut partial = Vec::new();
+    let mut batch = Vec::with_capacity(BATCH_LINES);
+    loop {
+        let read = reader.read_until(b'\n', &mut partial)?;
+        pos += read as u64;
+
+        if partial.ends_with(b"\n") {
+            batch.push(decode_line(std::mem::take(&mut partial)));
+        }
+
+        let at_end = read == 0 || reader.buffer().is_empty();
+        if (batch.len() >= BATCH_LINES || (at_end && !batch.is_empty()))
+            && !sender.send(std::mem::take(&mut batch))
+        {
+            return Ok(());
+        }
+
+        if read == 0 {
+            std::thread::sleep(FOLLOW_INTERVAL);
+            if std::fs::metadata(path)?.len() < pos {
+                log::info!("{} shrank, reading it again", path.display());
+                reader = BufReader::new(File::open(path)?);
+                pos = 0;
+                partial.clear();
+            }
+        }
+    }
+}
+
+/// Lines from every TCP connection accepted on `addr`.
+pub struct SocketSource {
+    pub addr: String,
+}
+
+impl LineSource for SocketSource {
+    fn name(&self) -> String {
+        format!("tcp://{}", self.addr)
+    }
+
+    fn spawn(self: Box<Self>, sender: LineSender) -> anyhow::Result<SourceHandle> {
+        let listener = TcpListener::bind(&self.addr)
+            .with_context(|| format!("can't listen on {}", self.addr))?;
+        std::thread::spawn(move || {
+            let mut backoff = Duration::ZERO;
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        backoff = Duration::ZERO;
+                        let sender = sender.clone();
+                        std::thread::spawn(move || {
+                            if let Err(err) = read_lines(stream, &sender) {
+                                sender.event(SourceEventKind::Error(err.to_string()));
+                            }
+                        });
+                    }
+                    Err(err) => {
+                        // Only the first of a run of errors is reported
+                        if backoff.is_zero() {
+                            sender.event(SourceEventKind::Error(err.to_string()));
+                        }
+                        backoff =
+                            (backoff * 2).clamp(Duration::from_millis(50), MAX_ACCEPT_BACKOFF);
+                        std::thread::sleep(backoff);
+                    }
+                }
+            }
+        });
+        Ok(SourceHandle::default())
+    }
+}
+
+/// A recorded file played back once, optionally paced to `rate` lines per second.
+pub struct ReplaySource {
+    pub path: PathBuf,
+    pub rate: Option<u32>,
+}
+
+impl LineSource for ReplaySource {
+    fn name(&self) -> String {
+        self.path.display().to_string()
+    }
+
+    fn spawn(self: Box<Self>, sender: LineSender) -> anyhow::Result<SourceHandle> {
+        let file = File::open(&self.path)
+            .with_context(|| format!("can't open {}", self.path.display()))?;
+        let Some(rate) = self.rate.filter(|&rate| rate > 0) else {
+            spawn_reader(file, sender);
+            return Ok(SourceHandle::default());
+        };
+
+        let interval = Duration::from_secs(1) / rate;
+        std::thread::spawn(move || {
+            for line in BufReader::new(file).split(b'\n') {
+                match line {
+                    Ok(line) => {
+                        if !sender.send(vec![decode_line(line)]) {
+                            return;
+                        }
+                    }
+                    Err(err) => {
+                        sender.event(SourceEventKind::Error(err.to_string()));
+                        return;
+                    }
+                }
+                std::thread::sleep(interval);
+            }
+            sender.event(SourceEventKind::Exited(None));
+        });
+        Ok(SourceHandle::default())
+    }
+}
+
+#[test]
+fn test_replay_source_sends_lines_then_exits() {
+    use crate::sync_child::{line_channel, OverflowPolicy};
+
+    let path = std::env::temp_dir().join(format!("filter-replay-{}.log", std::process::id()));
+    std::fs::write(&path, "first\nsecond\nthird").unwrap();
+
+    let (sender, rx, _stats) = line_channel(OverflowPolicy::Block);
+    let source = ReplaySource {
+        path: path.clone(),
+        rate: None,
+    };
+    let _handles = spawn_sources(vec![Box::new(source)], &sender).unwrap();
+    drop(sender);
+
+    let mut lines = Vec::new();
+    let mut events = Vec::new();
+    for message in rx {
+        match message {
+            SourceMessage::Lines {
+                source,
+                lines: batch,
+            } => {
+                assert_eq!(source, 0);
+                lines.extend(batch);
+            }
+            SourceMessage::Event(event) => events.push(event.kind),
+        }
+    }
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(lines, ["first", "second", "third"]);
+    assert!(events.contains(&SourceEventKind::Started));
+    assert_eq!(events.last(), Some(&SourceEventKind::Exited(None)));
+}
+
+#[test]
+fn test_file_source_follows_appends_and_truncation() {
+    use crate::sync_child::{line_channel, OverflowPolicy};
+    use std::io::Write;
+
+    let path = std::env::temp_dir().join(format!("filter-follow-{}.log", std::process::id()));
+    std::fs::write(&path, "first\n").unwrap();
+
+    let (sender, rx, _stats) = line_channel(OverflowPolicy::Block);
+    let source = FileSource { path: path.clone() };
+    let _handles = spawn_sources(vec![Box::new(source)], &sender).unwrap();
+    drop(sender);
+    let next_line = || loop {
+        match rx.recv_timeout(Duration::from_secs(5)).unwrap() {
+            SourceMessage::Lines { lines, .. } => break lines,
+            SourceMessage::Event(event) => assert_eq!(event.kind, SourceEventKind::Started),
+        }
+    };
+
+    assert_eq!(next_line(), ["first"]);
+    let mut file = std::fs::OpenOptions::new()
+        .append(true)
+        .open(&path)
+        .unwrap();
+    // A partial line waits for its end
+    file.write_all(b"sec").unwrap();
+    file.flush().unwrap();
+    std::thread::sleep(FOLLOW_INTERVAL * 2);
+    file.write_all(b"ond\n").unwrap();
+    assert_eq!(next_line(), ["second"]);
+
+    // A shorter file is read again from the start
+    std::fs::write(&path, "new\n").unwrap();
+    assert_eq!(next_line(), ["new"]);
+    std::fs::remove_file(&path).unwrap();
+}
@@ -1,12 +1,165 @@
 use std::{
-    io::{BufRead, BufReader, Read, Write},
+    io::{Read, Write},
     process::{Child, Command, ExitStatus, Stdio},
-    sync::mpsc::{Receiver, Sender},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{Receiver, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
     thread::JoinHandle,
+    time::Duration,
 };
 
 use anyhow::Context;
 
+use crate::source::{
+    read_lines, LineSource, SourceEvent, SourceEventKind, SourceHandle, SourceId, SourceMessage,
+};
+
+/// Maximum number of lines a reader thread packs into a single batch.
+pub const BATCH_LINES: usize = 1024;
+
+/// Number of batches that can wait in the channel before the overflow policy kicks in.
+pub const QUEUE_BATCHES: usize = 64;
+
+/// How often a child whose output closed is checked for having exited.
+const EXIT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// What reader threads do when the UI falls behind and the line queue is full.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum OverflowPolicy {
+    /// Stop reading until there is room, which in turn blocks the child on its pipe
+    #[default]
+    Block,
+    /// Discard the batch and count the lines as dropped
+    Drop,
+}
+
+/// Counters shared between the reader threads and the UI.
+#[derive(Default, Debug)]
+pub struct IngestStats {
+    /// Lines sent by readers but not appended to the pages yet
+    pub queued: AtomicUsize,
+    /// Lines discarded by `OverflowPolicy::Drop`
+    pub dropped: AtomicUsize,
+    /// Lines appended to the pages since start
+    pub appended: AtomicUsize,
+    /// Appended lines per level, indexed by `Level::index`
+    pub levels: [AtomicUsize; 5],
+}
+
+/// Sending half of the bounded line channel, applying the overflow policy on send.
+///
+/// Each clone is tagged with the source its lines come from, see `for_source`.
+#[derive(Clone)]
+pub struct LineSender {
+    tx: SyncSender<SourceMessage>,
+    policy: OverflowPolicy,
+    stats: Arc<IngestStats>,
+    source: SourceId,
+}
+
+impl LineSender {
+    /// Same channel, with lines and events tagged as coming from `source`
+    pub fn for_source(&self, source: SourceId) -> Self {
+        Self {
+            source,
+            ..self.clone()
+        }
+    }
+
+    /// returns false once the receiving side is gone
+    pub fn send(&self, batch: Vec<String>) -> bool {
+        let len = batch.len();
+        let message = SourceMessage::Lines {
+            source: self.source,
+            lines: batch,
+        };
+        match self.policy {
+            OverflowPolicy::Block => {
+                self.stats.queued.fetch_add(len, Ordering::Relaxed);
+                if self.tx.send(message).is_err() {
+                    self.stats.queued.fetch_sub(len, Ordering::Relaxed);
+                    return false;
+                }
+            }
+            OverflowPolicy::Drop => {
+                self.stats.queued.fetch_add(len, Ordering::Relaxed);
+                match self.tx.try_send(message) {
+                    Ok(()) => {}
+                    Err(TrySendError::Full(_)) => {
+                        self.stats.queued.fetch_sub(len, Ordering::Relaxed);
+                        self.stats.dropped.fetch_add(len, Ordering::Relaxed);
+                    }
+                    Err(TrySendError::Disconnected(_)) => {
+                        self.stats.queued.fetch_sub(len, Ordering::Relaxed);
+                        return false;
+                    }
+                }
+            }
+        }
+        true
+    }
+
+    /// Reports a lifecycle change, never dropped whatever the overflow policy
+    pub fn event(&self, kind: SourceEventKind) {
+        let event = SourceEvent {
+            source: self.source,
+            kind,
+        };
+        let _ = self.tx.send(SourceMessage::Event(event));
+    }
+}
+
+pub fn line_channel(
+    policy: OverflowPolicy,
+) -> (LineSender, Receiver<SourceMessage>, Arc<IngestStats>) {
+    let (tx, rx) = std::sync::mpsc::sync_channel(QUEUE_BATCHES);
+    let stats = Arc::new(IngestStats::default());
+    let sender = LineSender {
+        tx,
+        policy,
+        stats: stats.clone(),
+        source: 0,
+    };
+    (sender, rx, stats)
+}
+
+/// A child process whose stdout and stderr are read as lines, with keystrokes
+/// forwarded to its stdin.
+pub struct ChildSource {
+    pub args: Vec<String>,
+}
+
+impl LineSource for ChildSource {
+    fn name(&self) -> String {
+        self.args.join(" ")
+    }
+
+    fn spawn(self: Box<Self>, sender: LineSender) -> anyhow::Result<SourceHandle> {
+        let (stdin_tx, stdin_rx) = std::sync::mpsc::channel();
+        let handle = spawn_child_process(
+            &self.args,
+            Some(sender.clone()),
+            Some(sender.clone()),
+            Some(stdin_rx),
+        )?;
+        let handle = Arc::new(Mutex::new(handle));
+
+        let waited = handle.clone();
+        std::thread::spawn(move || match ChildHandle::wait(&waited) {
+            Ok(status) => sender.event(SourceEventKind::Exited(Some(status))),
+            Err(err) => sender.event(SourceEventKind::Error(err.to_string())),
+        });
+
+        Ok(SourceHandle::default()
+            .with_input(stdin_tx)
+            .on_stop(move || {
+                let _ = handle.lock().unwrap().child.kill();
+            }))
+    }
+}
+
 pub struct ChildHandle {
     pub stdin_handle: Option<JoinHandle<()>>,
     pub stderr_handle: Option<JoinHandle<()>>,
@@ -15,15 +168,25 @@ pub struct ChildHandle {
 }
 
 impl ChildHandle {
-    pub fn join(&mut self) -> anyhow::Result<ExitStatus> {
-        let _ = self.stdin_handle.take();
-        for handle in [self.stdout_handle.take(), self.stderr_handle.take()] {
-            if let Some(handle) = handle {
-                let _ = handle.join();
-            }
+    /// Waits for the output readers to finish, then for the child to exit.
+    ///
+    /// The lock is only held briefly, so the child can still be killed meanwhile.
+    fn wait(handle: &Mutex<ChildHandle>) -> std::io::Result<ExitStatus> {
+        let readers = {
+            let mut handle = handle.lock().unwrap();
+            let _ = handle.stdin_handle.take();
+            [handle.stdout_handle.take(), handle.stderr_handle.take()]
+        };
+        for reader in readers.into_iter().flatten() {
+            let _ = reader.join();
         }
 
-        Ok(self.child.wait()?)
+        loop {
+            if let Some(status) = handle.lock().unwrap().child.try_wait()? {
+                return Ok(status);
+            }
+            std::thread::sleep(EXIT_POLL_INTERVAL);
+        }
     }
 }
 
@@ -35,8 +198,8 @@ impl Drop for ChildHandle {
 
 pub fn spawn_child_process(
     args: &[String],
-    stdout_tx: Option<Sender<String>>,
-    stderr_tx: Option<Sender<String>>,
+    stdout_tx: Option<LineSender>,
+    stderr_tx: Option<LineSender>,
     stdin_rx: Option<Receiver<u8>>,
 ) -> anyhow::Result<ChildHandle> {
     let mut iter = args.iter();
@@ -67,12 +230,16 @@ pub fn spawn_child_process(
 
     if let Some(stdout_tx) = stdout_tx {
         if let Some(stdout) = child.stdout.take() {
-            stdout_handle = Some(std::thread::spawn(|| read_lines(stdout, stdout_tx)));
+            stdout_handle = Some(std::thread::spawn(move || {
+                read_child_lines(stdout, stdout_tx)
+            }));
         }
     }
     if let Some(stderr_tx) = stderr_tx {
         if let Some(stderr) = child.stderr.take() {
-            stderr_handle = Some(std::thread::spawn(|| read_lines(stderr, stderr_tx)));
+            stderr_handle = Some(std::thread::spawn(move || {
+                read_child_lines(stderr, stderr_tx)
+            }));
         }
     }
     if let Some(stdin_rx) = stdin_rx {
@@ -89,30 +256,13 @@ pub fn spawn_child_process(
     })
 }
 
-fn read_lines<T>(reader: T, sender: Sender<String>)
-where
-    T: Read + Unpin,
-{
-    let mut reader = BufReader::new(reader);
-    loop {
-        let mut buf = String::new();
-        if let Err(err) = reader.read_line(&mut buf) {
+fn read_child_lines<T: Read>(reader: T, sender: LineSender) {
+    match read_lines(reader, &sender) {
+        Ok(true) => log::info!("received child output eof"),
+        Ok(false) => log::error!("failed to send lines from child, receiver is gone"),
+        Err(err) => {
             log::error!("failed to read line from child {}", err);
-            break;
-        } else {
-            if buf.is_empty() {
-                log::info!("received child output eof");
-                break;
-            }
-
-            if buf.ends_with('\n') {
-                buf.pop();
-            }
-
-            if let Err(err) = sender.send(buf) {
-                log::error!("failed to send line from child {}", err);
-                break;
-            }
+            sender.event(SourceEventKind::Error(err.to_string()));
         }
     }
 }
@@ -141,3 +291,57 @@ where
 
 //     Ok(child_handle)
 // }
+
+#[test]
+fn test_line_sender_drop_policy() {
+    let (sender, rx, stats) = line_channel(OverflowPolicy::Drop);
+
+    for _ in 0..QUEUE_BATCHES {
+        assert!(sender.send(vec!["a".to_string(), "b".to_string()]));
+    }
+    // Channel is full, this batch is dropped rather than blocking
+    assert!(sender.send(vec!["c".to_string()]));
+
+    assert_eq!(stats.queued.load(Ordering::Relaxed), QUEUE_BATCHES * 2);
+    assert_eq!(stats.dropped.load(Ordering::Relaxed), 1);
+
+    drop(rx);
+    assert!(!sender.send(vec!["d".to_string()]));
+}
+
+#[test]
+fn test_child_source_reports_exit_status() {
+    use crate::source::spawn_sources;
+
+    let (sender, rx, _stats) = line_channel(OverflowPolicy::Block);
+    let args = [
+        "sh",
+        "-c",
+        r"printf 'a\377b\nafter\n'; echo err >&2; exit 3",
+    ];
+    let source = ChildSource {
+        args: args.iter().map(|arg| arg.to_string()).collect(),
+    };
+    let handles = spawn_sources(vec![Box::new(source)], &sender).unwrap();
+    drop(sender);
+
+    let mut lines = Vec::new();
+    let mut status = None;
+    for message in rx.iter() {
+        match message {
+            SourceMessage::Lines { lines: batch, .. } => lines.extend(batch),
+            SourceMessage::Event(event) => {
+                if let SourceEventKind::Exited(exited) = event.kind {
+                    status = exited;
+                    break;
+                }
+            }
+        }
+    }
+    drop(handles);
+
+    // An invalid byte doesn't end the output
+    lines.sort();
+    assert_eq!(lines, ["after", "a\u{FFFD}b", "err"]);
+    assert_eq!(status.and_then(|status| status.code()), Some(3));
+}
@@ -0,0 +1,241 @@
+//! Times of lines and filtering by them.
+//!
+//! The time of a line is the timestamp written in it, from its format's
+//! `timestamp` capture or a `time`, `ts`, `timestamp` or `@timestamp` field,
+//! or else the time it arrived. Times are written as:
+//!
+//! ```text
+//! 14:32  14:32:05          today
+//! 2024-05-01 14:32         a date and time, RFC 3339 works too
+//! 10m ago  90s ago  now    relative to now, with s, m, h or d
+//! ```
+
+use std::{ops::Range, sync::Arc};
+
+use chrono::{Local, NaiveDate, NaiveDateTime, NaiveTime, TimeZone};
+
+use crate::{command::Matcher, fields::parse_fields, format::FormatSet};
+
+/// Fields read as the time of a structured line.
+const TIME_FIELDS: [&str; 4] = ["time", "ts", "timestamp", "@timestamp"];
+
+/// Smallest number of a time field taken as seconds since the epoch.
+const MIN_EPOCH_SECONDS: f64 = 1e9;
+
+/// The time written in `line`, in milliseconds since the epoch, by the format
+/// of its source in `formats` if it has one.
+pub fn line_time(line: &str, formats: Option<&FormatSet>) -> Option<u64> {
+    if let Some(time) = formats.and_then(|formats| formats.timestamp(line)) {
+        return Some(time);
+    }
+    let fields = parse_fields(line, formats)?;
+    let field = fields
+        .iter()
+        .find(|field| TIME_FIELDS.contains(&field.key.as_str()))?;
+    parse_field_time(&field.value)
+}
+
+/// RFC 3339, a local date and time, or seconds or milliseconds since the epoch.
+///
+/// Numbers before 2001 in seconds, like a `time=0.5` duration, aren't times.
+fn parse_field_time(value: &str) -> Option<u64> {
+    if let Ok(number) = value.parse::<f64>() {
+        if number < MIN_EPOCH_SECONDS {
+            return None;
+        }
+        // Seconds until the year 5138, milliseconds after
+        let millis = if number < 1e11 {
+            number * 1000.0
+        } else {
+            number
+        };
+        return Some(millis as u64);
+    }
+    if let Ok(time) = chrono::DateTime::parse_from_rfc3339(value) {
+        return u64::try_from(time.timestamp_millis()).ok();
+    }
+    let time = NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S%.f").ok()?;
+    local_millis(time)
+}
+
+fn local_millis(time: NaiveDateTime) -> Option<u64> {
+    let time = Local.from_local_datetime(&time).earliest()?;
+    u64::try_from(time.timestamp_millis()).ok()
+}
+
+/// Parses a time as in the module docs, relative to `now` in milliseconds
+/// since the epoch.
+pub fn parse_time(s: &str, now: u64) -> Result<u64, String> {
+    let s = s.trim();
+    if s == "now" {
+        return Ok(now);
+    }
+    if let Some(ago) = s.strip_suffix("ago") {
+        let ago = ago.trim();
+        let units = [
+            ("s", 1000),
+            ("m", 60 * 1000),
+            ("h", 3600 * 1000),
+            ("d", 24 * 3600 * 1000),
+        ];
+        let Some((number, unit)) = units
+            .iter()
+            .find_map(|(suffix, unit)| Some((ago.strip_suffix(suffix)?, *unit)))
+        else {
+            return Err(format!(
+                "expected a duration like 10m, in s, m, h or d, in '{}'",
+                s
+            ));
+        };
+        let number: u64 = number
+            .trim()
+            .parse()
+            .map_err(|_| format!("expected a duration like 10m in '{}'", s))?;
+        let ago = number
+            .checked_mul(unit)
+            .ok_or_else(|| format!("'{}' is too long ago", s))?;
+        return Ok(now.saturating_sub(ago));
+    }
+    if let Ok(time) = chrono::DateTime::parse_from_rfc3339(s) {
+        let millis = u64::try_from(time.timestamp_millis());
+        return millis.map_err(|_| format!("'{}' is before 1970", s));
+    }
+    let time = ["%Y-%m-%d %H:%M:%S", "%Y-%m-%d %H:%M"]
+        .iter()
+        .find_map(|f| NaiveDateTime::parse_from_str(s, f).ok())
+        .or_else(|| {
+            let time = ["%H:%M:%S%.f", "%H:%M"]
+                .iter()
+                .find_map(|f| NaiveTime::parse_from_str(s, f).ok())?;
+            let today = chrono::DateTime::from_timestamp_millis(now as i64)?
+                .with_timezone(&Local)
+                .date_naive();
+            Some(NaiveDate::and_time(&today, time))
+        })
+        .ok_or_else(|| format!("unknown time '{}'", s))?;
+    local_millis(time).ok_or_else(|| format!("'{}' doesn't exist here", s))
+}
+
+/// Keeps the lines whose time is within a range.
+///
+/// Written as `since T`, `until T`, `between T and T` or `last 10m`.
+#[derive(Debug, Clone)]
+pub struct TimeRange {
+    /// Inclusive bounds, in milliseconds since the epoch
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+    text: String,
+    formats: Option<Arc<FormatSet>>,
+}
+
+impl TimeRange {
+    /// Parses `text`, with relative times counted back from `now`.
+    pub fn parse(text: &str, now: u64) -> Result<Self, String> {
+        let trimmed = text.trim();
+        let word = |prefix: &str| {
+            trimmed
+                .strip_prefix(prefix)
+                .filter(|rest| rest.starts_with(' '))
+        };
+        let (since, until) = if let Some(rest) = word("since") {
+            (Some(parse_time(rest, now)?), None)
+        } else if let Some(rest) = word("until") {
+            (None, Some(parse_time(rest, now)?))
+        } else if let Some(rest) = word("last") {
+            (
+                Some(parse_time(&format!("{} ago", rest.trim()), now)?),
+                None,
+            )
+        } else if let Some(rest) = word("between") {
+            let Some((start, end)) = rest.split_once(" and ") else {
+                return Err("expected between T and T".to_string());
+            };
+            (Some(parse_time(start, now)?), Some(parse_time(end, now)?))
+        } else {
+            return Err("expected since T, until T, between T and T or last N".to_string());
+        };
+        Ok(Self::from_bounds(since, until, trimmed))
+    }
+
+    /// A range that was already resolved, e.g. one stored in a session.
+    pub fn from_bounds(since: Option<u64>, until: Option<u64>, text: &str) -> Self {
+        Self {
+            since,
+            until,
+            text: text.to_string(),
+            formats: None,
+        }
+    }
+
+    /// Reads the times of lines with `formats` too, see [`crate::format`].
+    pub fn with_formats(mut self, formats: Option<Arc<FormatSet>>) -> Self {
+        self.formats = formats;
+        self
+    }
+
+    pub fn contains(&self, time: u64) -> bool {
+        let after_since = self.since.is_none_or(|since| time >= since);
+        after_since && self.until.is_none_or(|until| time <= until)
+    }
+}
+
+impl std::fmt::Display for TimeRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+impl Matcher for TimeRange {
+    /// Lines whose time can't be told pass.
+    fn is_match(&self, s: &str) -> Option<Range<usize>> {
+        self.is_match_at(s, None)
+    }
+
+    fn is_match_at(&self, s: &str, time: Option<u64>) -> Option<Range<usize>> {
+        match line_time(s, self.formats.as_deref()).or(time) {
+            Some(time) if !self.contains(time) => None,
+            _ => Some(0..0),
+        }
+    }
+}
+
+#[test]
+fn test_time_range() {
+    let now = parse_time("2024-05-01 14:10", 0).unwrap();
+    let at = |s| parse_time(s, now).unwrap();
+    assert_eq!(at("10m ago"), now - 10 * 60 * 1000);
+    assert_eq!(at("14:00"), at("2024-05-01 14:00:00"));
+    assert_eq!(at("14:00:30.5"), at("14:00") + 30_500);
+    assert_eq!(
+        parse_time("2024-05-01T12:00:00Z", now),
+        Ok(1_714_564_800_000)
+    );
+    assert!(parse_time("10y ago", now).is_err());
+    assert!(parse_time("soon", now).is_err());
+    assert!(parse_time("5é ago", now).is_err());
+    assert!(parse_time("99999999999999999d ago", now).is_err());
+    assert!(TimeRange::parse("since 3€ ago", now).is_err());
+
+    let range = TimeRange::parse("between 14:00 and 14:05", now).unwrap();
+    assert_eq!(range.to_string(), "between 14:00 and 14:05");
+    assert!(range.contains(at("14:03")) && !range.contains(at("14:06")));
+    // The written time wins over the arrival time
+    let line = format!(r#"{{"ts":{},"msg":"x"}}"#, at("14:01"));
+    assert!(range.is_match_at(&line, Some(at("15:00"))).is_some());
+    assert!(range.is_match_at("plain", Some(at("15:00"))).is_none());
+    assert!(range.is_match("plain").is_some());
+    // A duration isn't a time, the arrival time applies
+    assert!(range
+        .is_match_at("time=0.5 msg=done", Some(at("14:02")))
+        .is_some());
+    assert_eq!(line_time("time=0.5 msg=done", None), None);
+
+    let range = TimeRange::parse("last 5m", now).unwrap();
+    assert_eq!(range.since, Some(at("14:05")));
+    let line = "ts=2024-05-01T14:06:00+00:00 level=info";
+    assert_eq!(
+        range.is_match_at(line, None).is_some(),
+        range.contains(parse_time("2024-05-01T14:06:00Z", now).unwrap())
+    );
+    assert!(TimeRange::parse("around 14:00", now).is_err());
+}